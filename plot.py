@@ -4,7 +4,7 @@ from scipy.stats import norm, pearsonr
 import pandas as pd
 
 
-data = pd.read_csv("data/combined.csv")["price[0]"]
+data = pd.read_csv("data/combined.csv", comment="#")["price[0]"]
 rets = (data.shift(1) / data).dropna()
 log_rets = rets.apply(np.log)
 norm_log_rets = (log_rets - log_rets.mean()) / log_rets.std()
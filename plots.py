@@ -10,7 +10,7 @@ plt.rcParams["font.family"] = "sans-serif"
 
 for file in os.listdir("."):
     if file.endswith(".csv"):
-        data = pd.read_csv(file, header=0)
+        data = pd.read_csv(file, header=0, comment="#")
 
         nmbr_markets = 0
         for col in data.columns:
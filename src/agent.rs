@@ -2,28 +2,50 @@
 TODO: The interest vector is initially random. At every time step, the agents increase or decrease the
 interest in a market based on other agents' (close in the network) beliefs, overall profits from that
 asset, and other news (random noise in our case).
-TODO: Fundamentalists? From the DGA paper.
 */
 
 use std::{collections::VecDeque, iter::repeat_with, ops::Div};
 
-use rand::{distributions::Uniform, prelude::Rng, prelude::ThreadRng, thread_rng};
-use rand_distr::Standard;
+use rand::{
+    distributions::Uniform,
+    prelude::{Rng, SliceRandom},
+};
+use rand_distr::{Poisson, Standard};
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 
 use crate::{
-    config::Config,
-    market::{GenoaMarket, MarketId},
+    belief,
+    config::{AgentConfig, Config, InterestConfig, MarginConfig, OrderTypeConfig, StrategyKind},
+    index::Index,
+    margin::MarginAccount,
+    market::{Market, MarketId, Side},
+    simulation::SimRng,
+    strategy::{AnyStrategy, Strategy},
 };
 
 pub type AgentId = usize;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Agent<const M: usize> {
     pub cash: f32,
     /// Vector representing the amount of assets an agent holds.
     pub assets: SmallVec<[u32; M]>,
 
+    /// Cash that is bound by orders resting in an order book.
+    pub reserved_cash: f32,
+    /// Assets per market that are bound by orders resting in an order book.
+    pub reserved_assets: SmallVec<[u32; M]>,
+
+    /// Assets per market of the orders placed in the last step, that were not
+    /// executed right away.
+    pub unfilled: SmallVec<[u32; M]>,
+
+    /// Assets the agent sold without owning them or being allowed to borrow
+    /// them, since the last balance check. Markets must never let this
+    /// happen, see [`AgentCollection::take_overdrawn`].
+    pub oversold: u32,
+
     // /// Value that represents the market in which an agent invests next.
     // market_preference: u32,
     /// Vector encapsulating each market preference of an agent. Contains probabilities between [0, 1].
@@ -49,55 +71,497 @@ pub struct Agent<const M: usize> {
 
     /// Friend list containing trust values for other agents.
     pub friends: VecDeque<Friend>,
+
+    pub kind: AgentKind,
+
+    /// Decides in which direction a trader trades, see [`AgentKind::Trader`].
+    pub strategy: AnyStrategy,
+
+    /// Only agents with a margin account can borrow cash and assets.
+    pub margin: Option<MarginAccount>,
     // /// Value that describes how likely an agent is to change its preferences.
     // change_probability: f32,
 }
 
 impl<const M: usize> Agent<M> {
-    pub fn new(config: &Config, rng: &mut ThreadRng) -> Agent<M> {
+    /// A trader drawn from the distributions of the config.
+    pub fn new(config: &AgentConfig, market_count: usize, rng: &mut SimRng) -> Agent<M> {
         Agent {
-            cash: config.agent.initial_cash.sample_f32(rng),
+            cash: config.initial_cash.sample_f32(rng),
             // market_preference: 0,
-            assets: repeat_with(|| config.agent.initial_assets.sample_usize(rng) as u32)
-                .take(config.market.market_count)
+            assets: repeat_with(|| config.initial_assets.sample_usize(rng) as u32)
+                .take(market_count)
                 .collect(),
-            state: repeat_with(|| config.agent.initial_state.sample_f32(rng))
-                .take(config.market.market_count)
+            reserved_cash: 0.0,
+            reserved_assets: SmallVec::from_elem(0, market_count),
+            unfilled: SmallVec::from_elem(0, market_count),
+            oversold: 0,
+            state: repeat_with(|| config.initial_state.sample_f32(rng))
+                .take(market_count)
                 .collect(),
             // fundamentalism_ratio: 0.35,
-            order_probability: repeat_with(|| config.agent.order_probability.sample_f32(rng))
-                .take(config.market.market_count)
+            order_probability: repeat_with(|| config.order_probability.sample_f32(rng))
+                .take(market_count)
                 .collect(),
-            influence_probability: config.agent.influence_probability.sample_f32(rng),
-            influencers_count: config.agent.influencers_count.sample_usize(rng),
-            reflection_delay: config.agent.reflection_delay.sample_usize(rng),
+            influence_probability: config.influence_probability.sample_f32(rng),
+            influencers_count: config.influencers_count.sample_usize(rng),
+            reflection_delay: config.reflection_delay.sample_usize(rng),
             influences: VecDeque::new(),
-            friend_threshold: config.agent.friend_threshold.sample_f32(rng),
+            friend_threshold: config.friend_threshold.sample_f32(rng),
             friends: VecDeque::new(),
-            max_friends: config.agent.max_friends.sample_usize(rng),
-            friend_influence_probability: config.agent.friend_influence_probability.sample_f32(rng),
+            max_friends: config.max_friends.sample_usize(rng),
+            friend_influence_probability: config.friend_influence_probability.sample_f32(rng),
+            kind: AgentKind::Trader,
+            strategy: AnyStrategy::default(),
+            margin: config
+                .margin
+                .enabled
+                .then(|| MarginAccount::new(&config.margin, market_count)),
+        }
+    }
+
+    pub fn new_market_maker(config: &Config, rng: &mut SimRng) -> Agent<M> {
+        let mm = &config.agent.market_makers;
+        let mut agent = Agent::new(&config.agent, config.market.market_count, rng);
+        agent.margin = None;
+        agent.cash = mm.initial_cash.sample_f32(rng);
+        agent.assets = repeat_with(|| mm.initial_assets.sample_usize(rng) as u32)
+            .take(config.market.market_count)
+            .collect();
+        agent.kind = AgentKind::MarketMaker(MarketMaker {
+            spread: mm.spread.sample_f32(rng),
+            min_spread: mm.min_spread,
+            inventory_limit: mm.inventory_limit.sample_f32(rng),
+            order_size: mm.order_size.sample_usize(rng) as u32,
+            reference_volatility: config.market.initial_volatility,
+            initial_assets: agent.assets.to_vec(),
+            initial_wealth: agent.cash
+                + agent.assets.iter().sum::<u32>() as f32 * config.market.initial_price,
+        });
+        agent
+    }
+
+    pub fn new_arbitrageur(config: &Config, rng: &mut SimRng) -> Agent<M> {
+        let arb = &config.agent.arbitrageurs;
+        let mut agent = Agent::new(&config.agent, config.market.market_count, rng);
+        agent.margin = None;
+        agent.cash = arb.initial_cash.sample_f32(rng);
+        agent.assets = repeat_with(|| arb.initial_assets.sample_usize(rng) as u32)
+            .take(config.market.market_count)
+            .collect();
+        agent.kind = AgentKind::Arbitrageur(Arbitrageur {
+            threshold: arb.threshold,
+            order_size: arb.order_size.sample_usize(rng) as u32,
+            position: None,
+        });
+        agent
+    }
+
+    /// A fundamentalist with a fixed random belief about every market, see
+    /// [`AgentKind::Fundamentalist`]. Unless it trades, it holds nothing.
+    pub fn new_fundamentalist(config: &Config, rng: &mut SimRng) -> Agent<M> {
+        let fundamentalists = &config.agent.fundamentalists;
+        let market_count = config.market.market_count;
+        let mut agent = Agent::new(&config.agent, market_count, rng);
+        agent.state = (&mut *rng)
+            .sample_iter(Standard)
+            .map(|b: bool| b as usize as f32)
+            .take(market_count)
+            .collect();
+        agent.kind = AgentKind::Fundamentalist {
+            trading: fundamentalists.trading,
+        };
+        if !fundamentalists.trading {
+            agent.margin = None;
+            agent.cash = 0.0;
+            agent.assets = SmallVec::from_elem(0, market_count);
+            return agent;
+        }
+
+        agent.cash = fundamentalists.initial_cash.sample_f32(rng);
+        agent.assets = repeat_with(|| fundamentalists.initial_assets.sample_usize(rng) as u32)
+            .take(market_count)
+            .collect();
+        agent.strategy =
+            AnyStrategy::new(&fundamentalists.strategy, config.market.initial_price, rng);
+        agent
+    }
+
+    /// The value of the cash and all assets of the agent at current prices,
+    /// minus the value of borrowed assets.
+    pub fn wealth<Mk: Market>(&self, markets: &[Mk]) -> f32 {
+        let wealth = self.cash + self.long_value(markets);
+        match &self.margin {
+            Some(_) => wealth - self.short_value(markets),
+            None => wealth,
+        }
+    }
+
+    fn long_value<Mk: Market>(&self, markets: &[Mk]) -> f32 {
+        self.assets
+            .iter()
+            .zip(markets)
+            .map(|(&a, m)| a as f32 * m.price())
+            .sum::<f32>()
+    }
+
+    fn short_value<Mk: Market>(&self, markets: &[Mk]) -> f32 {
+        markets
+            .iter()
+            .map(|m| self.short(m.id()) as f32 * m.price())
+            .sum::<f32>()
+    }
+
+    /// The amount of borrowed assets the agent sold in a market.
+    pub fn short(&self, market: MarketId) -> u32 {
+        self.margin.as_ref().map_or(0, |m| m.short[market])
+    }
+
+    /// The value of all positions, long and short, at current prices.
+    pub fn exposure<Mk: Market>(&self, markets: &[Mk]) -> f32 {
+        self.long_value(markets) + self.short_value(markets)
+    }
+
+    /// The ratio between the exposure and the wealth, only available for
+    /// agents with a margin account and positive wealth.
+    pub fn leverage<Mk: Market>(&self, markets: &[Mk]) -> Option<f32> {
+        let wealth = self.wealth(markets);
+        self.margin
+            .as_ref()
+            .filter(|_| wealth > 0.0)
+            .map(|_| self.exposure(markets) / wealth)
+    }
+
+    /// The most cash the agent can spend on a new buy order.
+    fn buy_budget(&self) -> f32 {
+        match &self.margin {
+            Some(margin) => margin.buying_power(),
+            None => self.available_cash(),
+        }
+    }
+
+    /// The most assets the agent can offer in a new sell order, this includes
+    /// assets it is allowed to borrow.
+    fn sell_budget(&self, market: MarketId, price: f32) -> u32 {
+        let assets = self.available_assets(market);
+        match &self.margin {
+            Some(margin) if margin.allows_short() => {
+                assets.saturating_add((margin.buying_power() / price) as u32)
+            }
+            _ => assets,
+        }
+    }
+
+    /// Take a new buy order from the buying power of the agent.
+    fn commit_buy(&mut self, cash: f32) {
+        if let Some(margin) = &mut self.margin {
+            margin.use_buying_power(cash);
+        }
+    }
+
+    /// Take the borrowed part of a new sell order from the buying power of
+    /// the agent.
+    fn commit_sell(&mut self, market: MarketId, asset_quantity: u32, price: f32) {
+        let borrowed = asset_quantity.saturating_sub(self.available_assets(market));
+        if let Some(margin) = &mut self.margin {
+            margin.use_buying_power(borrowed as f32 * price);
+        }
+    }
+
+    /// Cash that is not reserved by resting orders.
+    pub fn available_cash(&self) -> f32 {
+        (self.cash - self.reserved_cash).max(0.0)
+    }
+
+    /// Assets of a market that are not reserved by resting orders.
+    pub fn available_assets(&self, market: MarketId) -> u32 {
+        self.assets[market].saturating_sub(self.reserved_assets[market])
+    }
+
+    /// Bind cash or assets for an order that rests in an order book.
+    pub fn reserve(&mut self, market: MarketId, side: Side, asset_quantity: u32, limit_price: f32) {
+        match side {
+            Side::Buy => self.reserved_cash += limit_price * asset_quantity as f32,
+            Side::Sell => self.reserved_assets[market] += asset_quantity,
         }
     }
 
+    /// Release cash or assets previously bound using [`Self::reserve`].
+    pub fn release(&mut self, market: MarketId, side: Side, asset_quantity: u32, limit_price: f32) {
+        match side {
+            Side::Buy => {
+                self.reserved_cash =
+                    (self.reserved_cash - limit_price * asset_quantity as f32).max(0.0)
+            }
+            Side::Sell => {
+                let r = &mut self.reserved_assets[market];
+                *r = r.saturating_sub(asset_quantity);
+            }
+        }
+    }
+
+    /// Buy assets, agents with a margin account may borrow the cash and
+    /// cover short positions first. Other agents must not end up with
+    /// negative cash, see [`AgentCollection::take_overdrawn`].
     pub fn apply_buy(&mut self, market: MarketId, asset_quantity: u32, price_per_item: f32) {
         self.cash -= price_per_item * asset_quantity as f32;
 
-        assert!(self.cash >= 0.0, "Agent ran out of cash");
+        match &mut self.margin {
+            Some(margin) => {
+                let covered = asset_quantity.min(margin.short[market]);
+                margin.short[market] -= covered;
+                let a = &mut self.assets[market];
+                *a = a.saturating_add(asset_quantity - covered);
+            }
+            None => {
+                let a = &mut self.assets[market];
+                *a = a.saturating_add(asset_quantity);
+            }
+        }
+    }
+
+    /// Count assets of an order that were not executed right away.
+    pub fn add_unfilled(&mut self, market: MarketId, asset_quantity: u32) {
+        let u = &mut self.unfilled[market];
+        *u = u.saturating_add(asset_quantity);
+    }
 
-        self.assets[market] += asset_quantity;
+    /// Whether the agent spent more cash or sold more assets than it had.
+    pub fn is_overdrawn(&self) -> bool {
+        self.oversold > 0 || (self.margin.is_none() && self.cash < 0.0)
     }
 
+    /// Sell assets, agents that are allowed to sell short borrow the missing
+    /// assets. Assets that other agents do not have are counted as
+    /// oversold.
     pub fn apply_sell(&mut self, market: MarketId, asset_quantity: u32, price_per_item: f32) {
         self.cash += price_per_item * asset_quantity as f32;
 
         let a = &mut self.assets[market];
-        *a = a
-            .checked_sub(asset_quantity)
-            .expect("Agent ran out of asset");
+        match &mut self.margin {
+            Some(margin) if margin.allows_short() && *a < asset_quantity => {
+                let short = &mut margin.short[market];
+                *short = short.saturating_add(asset_quantity - *a);
+                *a = 0;
+            }
+            _ => {
+                let sold = asset_quantity.min(*a);
+                self.oversold += asset_quantity - sold;
+                *a -= sold;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AgentKind {
+    /// Trades based on its beliefs, which are formed by gossip unless its
+    /// strategy ignores them.
+    Trader,
+    /// Provides liquidity by quoting both sides of every market.
+    MarketMaker(MarketMaker),
+    /// Trades the index against its components.
+    Arbitrageur(Arbitrageur),
+    /// Its belief never changes, other agents are influenced by it. If it
+    /// trades, it does so on its estimate of the value, like the
+    /// fundamentalist strategy.
+    Fundamentalist { trading: bool },
+    /// Went bankrupt and left, its cash and assets stay as they were.
+    Bankrupt,
+}
+
+impl AgentKind {
+    pub fn is_trader(&self) -> bool {
+        matches!(self, AgentKind::Trader)
+    }
+
+    pub fn is_fundamentalist(&self) -> bool {
+        matches!(self, AgentKind::Fundamentalist { .. })
+    }
+
+    pub fn is_bankrupt(&self) -> bool {
+        matches!(self, AgentKind::Bankrupt)
+    }
+
+    /// Whether other agents can be influenced by the agent.
+    pub fn is_gossip_source(&self) -> bool {
+        self.is_trader() || self.is_fundamentalist()
+    }
+
+    /// Whether the cash and assets of the agent take part in the run, they
+    /// earn interest and can go bankrupt. Bankrupt agents and fundamentalists
+    /// that do not trade keep what they hold.
+    pub fn is_active(&self) -> bool {
+        !matches!(
+            self,
+            AgentKind::Bankrupt | AgentKind::Fundamentalist { trading: false }
+        )
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketMaker {
+    spread: f32,
+    min_spread: f32,
+    inventory_limit: f32,
+    order_size: u32,
+    /// The volatility at which the full inventory limit is used.
+    reference_volatility: f32,
+    initial_assets: Vec<u32>,
+    initial_wealth: f32,
+}
+
+impl MarketMaker {
+    /// The amount of assets held above the initial assets.
+    pub fn inventory<const M: usize>(&self, agent: &Agent<M>, market: MarketId) -> i64 {
+        agent.assets[market] as i64 - self.initial_assets[market] as i64
+    }
+
+    /// Profit and loss since the start of the simulation.
+    pub fn pnl<Mk: Market, const M: usize>(&self, agent: &Agent<M>, markets: &[Mk]) -> f32 {
+        agent.wealth(markets) - self.initial_wealth
+    }
+
+    /// Post a buy order below and a sell order above the current price. The
+    /// distance grows with the volatility, and both orders are shifted to
+    /// reduce the inventory. Once the inventory limit is reached only the
+    /// reducing side is quoted.
+    fn quote<Mk: Market, const M: usize>(
+        &self,
+        agent_id: AgentId,
+        agent: &Agent<M>,
+        market: &mut Mk,
+    ) {
+        let m_id = market.id();
+        let price = market.price();
+        let volatility = market.volatility();
+
+        // Old quotes are replaced, so all assets of the market can be offered.
+        // Cash is shared between the markets, only what is not reserved by
+        // resting orders is used for the bid.
+        market.cancel_orders(agent_id);
+
+        let half_spread = price * (self.spread * volatility).max(self.min_spread);
+        let limit = self.inventory_limit * (self.reference_volatility / volatility).min(1.0);
+        let inventory = self.inventory(agent, m_id) as f32;
+        let skew = half_spread * (inventory / limit).clamp(-1.0, 1.0);
+
+        if inventory < limit {
+            let bid = price - half_spread - skew;
+            let size = self.order_size.min((agent.available_cash() / bid) as u32);
+            if bid > 0.0 && size > 0 {
+                market.limit_order(agent_id, Side::Buy, size, bid);
+            }
+        }
+        if inventory > -limit {
+            let ask = price + half_spread - skew;
+            let size = self.order_size.min(agent.assets[m_id]);
+            if size > 0 {
+                market.limit_order(agent_id, Side::Sell, size, ask);
+            }
+        }
+    }
+}
+
+/// Sells the index and buys its components when the index trades at a
+/// premium to the value of its basket, and the other way around at a
+/// discount. Baskets are converted to index shares and back at the start of
+/// every step, so the positions do not run out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Arbitrageur {
+    threshold: f32,
+    order_size: u32,
+    /// The side of the index traded during the current step, with the net
+    /// asset value of the index at the start of the step.
+    position: Option<(Side, f32)>,
+}
+
+impl Arbitrageur {
+    /// Decide which side of the index to trade, and create or redeem index
+    /// shares for it. Returns the amount of baskets converted to index
+    /// shares, negative if index shares were redeemed for baskets.
+    fn convert<const M: usize>(agent: &mut Agent<M>, index: &Index, premium: f32, nav: f32) -> i64 {
+        let arb = match &mut agent.kind {
+            AgentKind::Arbitrageur(arb) => arb,
+            _ => return 0,
+        };
+        arb.position = if premium > arb.threshold {
+            Some((Side::Sell, nav))
+        } else if premium < -arb.threshold {
+            Some((Side::Buy, nav))
+        } else {
+            None
+        };
+
+        let order_size = arb.order_size;
+        let creation_unit = index.creation_unit();
+        let baskets = match arb.position {
+            Some((Side::Sell, _)) => index
+                .basket()
+                .iter()
+                .map(|&(m, units)| agent.available_assets(m) / units.max(1))
+                .fold(order_size, u32::min) as i64,
+            Some((Side::Buy, _)) => {
+                let shares = agent.available_assets(index.market());
+                -((shares / creation_unit).min(order_size) as i64)
+            }
+            None => 0,
+        };
+        let a = &mut agent.assets[index.market()];
+        *a = (*a as i64 + baskets * creation_unit as i64) as u32;
+        for &(m, units) in index.basket() {
+            let a = &mut agent.assets[m];
+            *a = (*a as i64 - baskets * units as i64) as u32;
+        }
+        baskets
+    }
+
+    /// Trade the index or one of its components in the direction decided at
+    /// the start of the step. The index is traded up to half the threshold
+    /// away from its net asset value, the components up to half the threshold
+    /// away from their price.
+    fn trade<Mk: Market, const M: usize>(
+        &self,
+        agent_id: AgentId,
+        agent: &Agent<M>,
+        market: &mut Mk,
+        index: &Index,
+    ) {
+        let m_id = market.id();
+        market.cancel_orders(agent_id);
+
+        let (index_side, nav) = match self.position {
+            Some(position) => position,
+            None => return,
+        };
+        let margin = self.threshold / 2.0;
+        let (side, quantity, limit) = if m_id == index.market() {
+            let limit = match index_side {
+                Side::Buy => nav * (1.0 - margin),
+                Side::Sell => nav * (1.0 + margin),
+            };
+            (index_side, self.order_size * index.creation_unit(), limit)
+        } else if let Some(&(_, units)) = index.basket().iter().find(|(m, _)| *m == m_id) {
+            let price = market.price();
+            match index_side {
+                Side::Buy => (Side::Sell, units * self.order_size, price * (1.0 - margin)),
+                Side::Sell => (Side::Buy, units * self.order_size, price * (1.0 + margin)),
+            }
+        } else {
+            return;
+        };
+
+        let size = match side {
+            Side::Buy => quantity.min((agent.available_cash() / limit) as u32),
+            Side::Sell => quantity.min(agent.available_assets(m_id)),
+        };
+        if size > 0 {
+            market.limit_order(agent_id, side, size, limit);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Influence {
     influencer: AgentId,
     state: Vec<f32>,
@@ -105,59 +569,412 @@ pub struct Influence {
     step: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Friend {
     agent: AgentId,
     score: i32,
 }
 
-#[derive(Debug, Clone)]
+impl Friend {
+    pub fn agent(&self) -> AgentId {
+        self.agent
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AgentCollection<const M: usize> {
     agents: Vec<Agent<M>>,
-    fundamentalists: Vec<SmallVec<[f32; M]>>,
+    margin: MarginConfig,
+    order_types: OrderTypeConfig,
+    /// Cash given to or taken from the agents during the current step outside
+    /// of trades, like redistributed fees, borrow fees, interest or the cash of
+    /// new traders. Fees charged by the markets are not included.
+    transfers: f64,
+    /// The assets per market created or destroyed during the current step, by
+    /// the creation and redemption of index shares or brought by new traders.
+    issued: Vec<i64>,
+    index: Option<Index>,
+    /// The amount of liquidations started during the last step.
+    liquidations: usize,
+    interest: InterestConfig,
+    /// The interest paid to the agents during the last step, minus the
+    /// interest they paid for borrowed cash.
+    interest_paid: f64,
+    /// The config new traders are drawn from.
+    #[serde(with = "crate::config::as_toml")]
+    config: AgentConfig,
+    /// The value fundamentalists assume for markets without a fundamental
+    /// value.
+    initial_price: f32,
+    /// The amount of agents that went bankrupt and of traders that entered
+    /// during the last step.
+    bankruptcies: usize,
+    entries: usize,
+    rng: SimRng,
 }
 
 impl<const M: usize> AgentCollection<M> {
-    pub fn new(config: &Config) -> AgentCollection<M> {
-        let mut rng = thread_rng();
-        AgentCollection {
-            agents: repeat_with(|| Agent::new(config, &mut rng))
+    pub fn new(config: &Config, mut rng: SimRng) -> AgentCollection<M> {
+        let mut collection = AgentCollection {
+            agents: repeat_with(|| Agent::new(&config.agent, config.market.market_count, &mut rng))
                 .take(config.agent.agent_count)
                 .collect(),
-            fundamentalists: repeat_with(|| {
-                thread_rng()
-                    .sample_iter(Standard)
-                    .map(|b: bool| b as usize as f32)
-                    .take(config.market.market_count)
-                    .collect()
-            })
-            .take(config.agent.fundamentalist_count)
-            .collect(),
+            margin: config.agent.margin.clone(),
+            order_types: config.agent.order_types.clone(),
+            transfers: 0.0,
+            issued: vec![0; config.market.market_count],
+            index: Index::from_config(config),
+            liquidations: 0,
+            interest: config.interest.clone(),
+            interest_paid: 0.0,
+            config: config.agent.clone(),
+            initial_price: config.market.initial_price,
+            bankruptcies: 0,
+            entries: 0,
+            rng,
+        };
+        let market_makers = repeat_with(|| Agent::new_market_maker(config, &mut collection.rng))
+            .take(config.agent.market_makers.count)
+            .collect::<Vec<_>>();
+        collection.agents.extend(market_makers);
+        let arbitrageurs = repeat_with(|| Agent::new_arbitrageur(config, &mut collection.rng))
+            .take(config.agent.arbitrageurs.count)
+            .collect::<Vec<_>>();
+        collection.agents.extend(arbitrageurs);
+        let fundamentalists =
+            repeat_with(|| Agent::new_fundamentalist(config, &mut collection.rng))
+                .take(config.agent.fundamentalist_count)
+                .collect::<Vec<_>>();
+        collection.agents.extend(fundamentalists);
+
+        let mut traders = 0..config.agent.agent_count;
+        for strategy in &config.agent.strategies {
+            for id in traders.by_ref().take(strategy.count) {
+                let strategy =
+                    AnyStrategy::new(strategy, collection.initial_price, &mut collection.rng);
+                collection.agents[id].strategy = strategy;
+            }
+        }
+        collection
+    }
+
+    /// The strategy of a new trader, each strategy is chosen with the share of
+    /// the agents that use it at the start.
+    fn sample_strategy(&mut self) -> AnyStrategy {
+        if self.config.strategies.is_empty() {
+            return AnyStrategy::default();
+        }
+        let mut r = self.rng.gen::<f32>() * self.config.agent_count as f32;
+        for strategy in &self.config.strategies {
+            if r < strategy.count as f32 {
+                return AnyStrategy::new(strategy, self.initial_price, &mut self.rng);
+            }
+            r -= strategy.count as f32;
         }
+        AnyStrategy::default()
     }
 
     pub fn agent(&self, id: AgentId) -> &Agent<M> {
-        &self.agents[id as usize]
+        &self.agents[id]
     }
 
     pub fn agent_mut(&mut self, id: AgentId) -> &mut Agent<M> {
-        &mut self.agents[id as usize]
+        &mut self.agents[id]
     }
 
     pub fn agents(&self) -> &[Agent<M>] {
         &self.agents[..]
     }
 
+    /// All agents that trade on their beliefs.
+    pub fn traders(&self) -> impl Iterator<Item = &Agent<M>> {
+        self.agents.iter().filter(|a| a.kind.is_trader())
+    }
+
+    /// Whether traders use any strategy other than noise trading.
+    pub fn has_strategies(&self) -> bool {
+        !self.config.strategies.is_empty()
+    }
+
+    /// The traders that use a strategy.
+    pub fn traders_with(&self, strategy: StrategyKind) -> impl Iterator<Item = &Agent<M>> {
+        self.traders()
+            .filter(move |a| a.strategy.kind() == strategy)
+    }
+
+    /// The mean wealth of the traders that use a strategy.
+    pub fn mean_wealth_with<Mk: Market>(
+        &self,
+        strategy: StrategyKind,
+        markets: &[Mk],
+    ) -> Option<f32> {
+        mean_wealth(self.traders_with(strategy), markets)
+    }
+
+    /// The fundamentalists that did not go bankrupt.
+    pub fn fundamentalists(&self) -> impl Iterator<Item = &Agent<M>> {
+        self.agents.iter().filter(|a| a.kind.is_fundamentalist())
+    }
+
+    /// The mean wealth of the fundamentalists, if they trade.
+    pub fn mean_fundamentalist_wealth<Mk: Market>(&self, markets: &[Mk]) -> Option<f32> {
+        mean_wealth(
+            self.fundamentalists().filter(|a| a.kind.is_active()),
+            markets,
+        )
+    }
+
+    pub fn market_makers(&self) -> impl Iterator<Item = (&Agent<M>, &MarketMaker)> {
+        self.agents.iter().filter_map(|a| match &a.kind {
+            AgentKind::MarketMaker(mm) => Some((a, mm)),
+            _ => None,
+        })
+    }
+
+    pub fn market_maker_count(&self) -> usize {
+        self.market_makers().count()
+    }
+
+    /// The total inventory of all market makers in a market.
+    pub fn market_maker_inventory(&self, market: MarketId) -> i64 {
+        self.market_makers()
+            .map(|(a, mm)| mm.inventory(a, market))
+            .sum()
+    }
+
+    /// The total profit and loss of all market makers.
+    pub fn market_maker_pnl<Mk: Market>(&self, markets: &[Mk]) -> f32 {
+        self.market_makers().map(|(a, mm)| mm.pnl(a, markets)).sum()
+    }
+
     /// Call this function first, once every step.
-    pub fn step(&mut self, markets: &[GenoaMarket], step: usize) {
+    pub fn step<Mk: Market>(&mut self, markets: &[Mk], step: usize) {
+        self.transfers = 0.0;
+        self.issued.iter_mut().for_each(|i| *i = 0);
+        if self.interest.is_enabled() {
+            self.pay_interest(step);
+        }
+        if self.margin.enabled {
+            self.update_margin(markets);
+        }
+        self.update_arbitrageurs(markets);
         self.dga(markets, step);
     }
 
+    /// Arbitrageurs create or redeem index shares for the trades of this
+    /// step, see [`Arbitrageur`].
+    fn update_arbitrageurs<Mk: Market>(&mut self, markets: &[Mk]) {
+        let index = match &self.index {
+            Some(index) => index,
+            None => return,
+        };
+        let (premium, nav) = (index.premium(markets), index.nav(markets));
+        for agent in &mut self.agents {
+            let baskets = Arbitrageur::convert(agent, index, premium, nav);
+            self.issued[index.market()] += baskets * index.creation_unit() as i64;
+            for &(m, units) in index.basket() {
+                self.issued[m] -= baskets * units as i64;
+            }
+        }
+    }
+
+    /// The index market, if one is configured.
+    pub fn index(&self) -> Option<&Index> {
+        self.index.as_ref()
+    }
+
+    /// The assets of a market created during the current step, negative if
+    /// assets were destroyed. Only the creation and redemption of index
+    /// shares and new traders change the assets of a market.
+    pub fn issued(&self, market: MarketId) -> i64 {
+        self.issued[market]
+    }
+
+    /// Charges borrow fees and checks the margin requirement of every agent
+    /// with a margin account.
+    fn update_margin<Mk: Market>(&mut self, markets: &[Mk]) {
+        self.liquidations = 0;
+        for agent in self.agents.iter_mut().filter(|a| a.kind.is_active()) {
+            let fee = self.margin.borrow_fee * agent.short_value(markets);
+            agent.cash -= fee;
+            self.transfers -= fee as f64;
+
+            let (wealth, exposure) = (agent.wealth(markets), agent.exposure(markets));
+            if let Some(margin) = &mut agent.margin {
+                if margin.update(&self.margin, wealth, exposure) {
+                    self.liquidations += 1;
+                }
+            }
+        }
+    }
+
+    /// Pays the risk-free rate on the cash of every active agent, agents that
+    /// borrowed cash on margin pay the rate plus the
+    /// spread. Agents without a margin account that are overdrawn pay
+    /// nothing, they are handled as insolvent instead.
+    fn pay_interest(&mut self, step: usize) {
+        let rate = self.interest.rate_at(step);
+        let borrow_rate = rate + self.interest.borrow_spread;
+        let mut paid = 0.0;
+        for agent in self.agents.iter_mut().filter(|a| a.kind.is_active()) {
+            let interest = if agent.cash >= 0.0 {
+                rate * agent.cash
+            } else if agent.margin.is_some() {
+                borrow_rate * agent.cash
+            } else {
+                0.0
+            };
+            agent.cash += interest;
+            paid += interest as f64;
+        }
+        self.interest_paid = paid;
+        self.transfers += paid;
+    }
+
+    /// Whether the agents earn interest on their cash.
+    pub fn has_interest(&self) -> bool {
+        self.interest.is_enabled()
+    }
+
+    /// The interest paid to the agents during the last step, negative if they
+    /// paid more for borrowed cash than they earned.
+    pub fn interest_paid(&self) -> f64 {
+        self.interest_paid
+    }
+
+    /// Whether agents have margin accounts.
+    pub fn has_margin(&self) -> bool {
+        self.margin.enabled
+    }
+
+    /// The amount of agents whose positions started to be closed during the
+    /// last step, because they did not meet the margin requirement.
+    pub fn liquidations(&self) -> usize {
+        self.liquidations
+    }
+
+    /// The mean leverage of all agents with a margin account and positive
+    /// wealth.
+    pub fn mean_leverage<Mk: Market>(&self, markets: &[Mk]) -> Option<f32> {
+        let leverage = self
+            .agents
+            .iter()
+            .filter_map(|a| a.leverage(markets))
+            .collect::<Vec<_>>();
+        Some(leverage.iter().sum::<f32>() / leverage.len() as f32).filter(|_| !leverage.is_empty())
+    }
+
     /// Call this function after [`Self::step`], once for every market.
-    pub fn step_market(&mut self, market: &mut GenoaMarket) {
+    pub fn step_market<Mk: Market>(&mut self, market: &mut Mk) {
         self.trade_on_market(market);
     }
 
+    /// Pay out cash in equal parts to all active agents.
+    pub fn distribute_cash(&mut self, cash: f32) {
+        let active = self.agents.iter().filter(|a| a.kind.is_active()).count();
+        let share = cash / active as f32;
+        for agent in self.agents.iter_mut().filter(|a| a.kind.is_active()) {
+            agent.cash += share;
+        }
+        self.transfers += share as f64 * active as f64;
+    }
+
+    /// Cash given to or taken from the agents during the current step, other
+    /// than by trades and market fees.
+    pub fn transfers(&self) -> f64 {
+        self.transfers
+    }
+
+    /// The agents that spent more cash or sold more assets than they had since
+    /// the last call, see [`Agent::is_overdrawn`]. Oversold assets are only
+    /// reported once.
+    pub fn take_overdrawn(&mut self) -> Vec<AgentId> {
+        let overdrawn = self
+            .agents
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| a.is_overdrawn())
+            .map(|(id, _)| id)
+            .collect();
+        for agent in &mut self.agents {
+            agent.oversold = 0;
+        }
+        overdrawn
+    }
+
+    /// Whether agents can go bankrupt and new traders enter.
+    pub fn has_population_changes(&self) -> bool {
+        self.config.population.enabled
+    }
+
+    /// Agents that are insolvent or whose wealth fell below the bankruptcy
+    /// threshold go bankrupt, and new traders enter, see
+    /// [`crate::config::PopulationConfig`]. Call this once at the end of a
+    /// step. Returns the agents that went bankrupt, their orders still have to
+    /// be cancelled.
+    pub fn update_population<Mk: Market>(
+        &mut self,
+        markets: &[Mk],
+        insolvent: &[AgentId],
+    ) -> Vec<AgentId> {
+        self.bankruptcies = 0;
+        self.entries = 0;
+        if !self.config.population.enabled {
+            return Vec::new();
+        }
+
+        let threshold = self.config.population.bankruptcy_threshold;
+        let bankrupt = self
+            .agents
+            .iter()
+            .enumerate()
+            .filter(|(id, a)| {
+                a.kind.is_active() && (insolvent.contains(id) || a.wealth(markets) < threshold)
+            })
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        for &id in &bankrupt {
+            let agent = &mut self.agents[id];
+            agent.kind = AgentKind::Bankrupt;
+            agent.friends.clear();
+            agent.influences.clear();
+        }
+        for agent in &mut self.agents {
+            agent.friends.retain(|f| !bankrupt.contains(&f.agent));
+            agent
+                .influences
+                .retain(|i| !bankrupt.contains(&i.influencer));
+        }
+        self.bankruptcies = bankrupt.len();
+
+        let rate = self.config.population.entry_rate;
+        if rate > 0.0 {
+            let poisson = Poisson::new(rate).expect("can't sample entries with this rate");
+            self.entries = self.rng.sample::<f32, _>(poisson) as usize;
+        }
+        for _ in 0..self.entries {
+            let mut agent = Agent::new(&self.config, self.issued.len(), &mut self.rng);
+            agent.strategy = self.sample_strategy();
+            self.transfers += agent.cash as f64;
+            for (issued, &assets) in self.issued.iter_mut().zip(&agent.assets) {
+                *issued += assets as i64;
+            }
+            self.agents.push(agent);
+        }
+
+        bankrupt
+    }
+
+    /// The amount of agents that went bankrupt during the last step.
+    pub fn bankruptcies(&self) -> usize {
+        self.bankruptcies
+    }
+
+    /// The amount of traders that entered during the last step.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
     pub fn total_cash(&self) -> f64 {
         self.agents.iter().map(|a| a.cash as f64).sum()
     }
@@ -166,93 +983,136 @@ impl<const M: usize> AgentCollection<M> {
         self.agents.iter().map(|a| a.assets[market]).sum()
     }
 
-    pub fn mean_state(&self, market: MarketId) -> f32 {
-        let states = self
-            .agents
+    /// The assets of a market held by all agents minus the borrowed ones,
+    /// trades do not change this.
+    pub fn net_assets(&self, market: MarketId) -> i64 {
+        self.agents
             .iter()
-            .map(|a| a.state[market] as f32)
-            .sum::<f32>();
-        states / self.agents.len() as f32
+            .map(|a| a.assets[market] as i64 - a.short(market) as i64)
+            .sum()
+    }
+
+    pub fn mean_state(&self, market: MarketId) -> f32 {
+        let states = self.traders().map(|a| a.state[market]).sum::<f32>();
+        states / self.traders().count() as f32
     }
 
     pub fn cash_median(&self) -> f32 {
-        let mut cash: Vec<_> = self.agents.iter().map(|a| a.cash).collect();
+        let mut cash: Vec<_> = self.traders().map(|a| a.cash).collect();
         cash.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        cash[cash.len() / 2]
+        cash.get(cash.len() / 2).copied().unwrap_or(f32::NAN)
     }
 
-    pub fn wealth_median(&self, markets: &[GenoaMarket]) -> f32 {
-        let mut wealth: Vec<_> = self
-            .agents
-            .iter()
-            .map(|a| {
-                a.cash
-                    + a.assets
-                        .iter()
-                        .zip(markets)
-                        .map(|(&a, m)| a as f32 * m.price())
-                        .sum::<f32>()
-            })
-            .collect();
+    pub fn wealth_median<Mk: Market>(&self, markets: &[Mk]) -> f32 {
+        let mut wealth: Vec<_> = self.traders().map(|a| a.wealth(markets)).collect();
         wealth.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        wealth[wealth.len() / 2]
+        wealth.get(wealth.len() / 2).copied().unwrap_or(f32::NAN)
     }
 
-    /// Give the state of either an agent or a fundamentalist. `idx` must be in
-    /// range 0 to len(agents) + len(fundamentalists)
+    /// Shift the state of a random fraction of the traders in the given
+    /// markets, keeping it in the range 0 to 1. Returns the amount of traders
+    /// that were shifted.
+    pub fn shift_states(
+        &mut self,
+        markets: &[MarketId],
+        fraction: f32,
+        shift: f32,
+        rng: &mut SimRng,
+    ) -> usize {
+        let traders = (0..self.agents.len())
+            .filter(|&i| self.agents[i].kind.is_trader())
+            .collect::<Vec<_>>();
+        let amount = ((fraction * traders.len() as f32).round() as usize).min(traders.len());
+        for &i in traders.choose_multiple(rng, amount) {
+            for &m in markets {
+                let state = &mut self.agents[i].state[m];
+                *state = (*state + shift).clamp(0.0, 1.0);
+            }
+        }
+        amount
+    }
+
+    /// Flip the state of a random fraction of the fundamentalists in the given
+    /// markets. Returns the amount of fundamentalists that were flipped.
+    pub fn flip_fundamentalists(
+        &mut self,
+        markets: &[MarketId],
+        fraction: f32,
+        rng: &mut SimRng,
+    ) -> usize {
+        let fundamentalists = (0..self.agents.len())
+            .filter(|&i| self.agents[i].kind.is_fundamentalist())
+            .collect::<Vec<_>>();
+        let count = fundamentalists.len();
+        let amount = ((fraction * count as f32).round() as usize).min(count);
+        for i in rand::seq::index::sample(rng, count, amount) {
+            for &m in markets {
+                let state = &mut self.agents[fundamentalists[i]].state[m];
+                *state = 1.0 - *state;
+            }
+        }
+        amount
+    }
+
+    /// Give the state of an agent, which influences others if it is a trader
+    /// or a fundamentalist.
     pub fn influence_at_market(&self, idx: usize, market: MarketId) -> f32 {
         self.influence_at(idx)[market]
     }
 
     pub fn influence_at(&self, idx: usize) -> &[f32] {
-        if idx < self.agents.len() {
-            &self.agents[idx].state
-        } else {
-            &self.fundamentalists[idx - self.agents.len()]
-        }
+        &self.agents[idx].state
     }
 
     /// Every agent updates their beliefs based on other agents' preferences
     /// and their own interests. At every time step, the interest for a market
     /// is updated based on performance (overall profits from a market), news
     /// and random noise.
-    pub fn dga(&mut self, markets: &[GenoaMarket], step: usize) {
-        // More efficient this way.
-        let mut rng = thread_rng();
-
-        let range = Uniform::from(0..self.agents.len() + self.fundamentalists.len());
+    pub fn dga<Mk: Market>(&mut self, markets: &[Mk], step: usize) {
         let market_count = markets.len();
+        // The agents that can be sampled as influencers, traders among them.
+        let sources = (0..self.agents.len())
+            .filter(|&i| self.agents[i].kind.is_gossip_source())
+            .collect::<Vec<_>>();
+        let range = Uniform::from(0..sources.len().max(1));
 
         for idx in 0..self.agents.len() {
+            // Only traders are influenced, fundamentalists keep their beliefs
+            // and market makers do not take part in the gossip.
+            if !self.agents[idx].kind.is_trader() {
+                continue;
+            }
+
             // Check if the current agent is to be influenced based on the influence probability.
-            if rng.gen::<f32>() < self.agents[idx].influence_probability {
-                // Generate our influencers
-                let mut influencers = rng
-                    .clone()
+            if self.rng.gen::<f32>() < self.agents[idx].influence_probability {
+                // Generate our influencers, at most as many as there are
+                // other sources left.
+                let sources = &sources;
+                let mut influencers = (&mut self.rng)
                     .sample_iter(&range)
+                    .map(|i| sources[i])
                     // Make sure we do not influence ourselves
-                    .filter(|&i: &usize| i != idx)
-                    .take(self.agents[idx].influencers_count)
+                    .filter(|&i| i != idx)
+                    .take(self.agents[idx].influencers_count.min(sources.len() - 1))
                     .collect::<Vec<_>>();
 
                 // Also be influenced by friends
                 for f in &self.agents[idx].friends {
-                    if rng.gen::<f32>() < self.agents[idx].friend_influence_probability {
+                    if self.rng.gen::<f32>() < self.agents[idx].friend_influence_probability {
                         influencers.push(f.agent);
                     }
                 }
 
                 // Influence all markets
                 for market in 0..market_count {
-                    // Take random agents and sum their influence.
-                    let influence_sum = influencers
+                    // Take the beliefs of the influencers and update our own.
+                    let influences = influencers
                         .iter()
                         .map(|&i| self.influence_at_market(i, market))
-                        .sum::<f32>();
-
-                    let influence = influence_sum / influencers.len() as f32;
+                        .collect::<Vec<_>>();
 
-                    self.agents[idx].state[market] = influence.round();
+                    let state = &mut self.agents[idx].state[market];
+                    *state = belief::update(&self.config.beliefs, *state, &influences);
                 }
 
                 for i in influencers {
@@ -268,7 +1128,7 @@ impl<const M: usize> AgentCollection<M> {
     }
 
     /// Checks the performance of friends and influencers based on the previous time step.
-    pub fn update_friends(&mut self, markets: &[GenoaMarket]) {
+    pub fn update_friends<Mk: Market>(&mut self, markets: &[Mk]) {
         let market_count = markets.len();
 
         // Calculate the market movements of all the markets from the previous step.
@@ -276,7 +1136,7 @@ impl<const M: usize> AgentCollection<M> {
             .iter()
             .map(|m| m.price() - m.price_ago(1))
             .collect::<Vec<_>>();
-            
+
         // Caculate the mean of the market movements.
         let market_movement_mean = market_movement.iter().sum::<f32>().div(market_count as f32);
 
@@ -353,21 +1213,81 @@ impl<const M: usize> AgentCollection<M> {
         }
     }
 
-    pub fn trade_on_market(&mut self, market: &mut GenoaMarket) {
-        let mut rng = thread_rng();
+    pub fn trade_on_market<Mk: Market>(&mut self, market: &mut Mk) {
+        let rng = &mut self.rng;
+        let slippage = self.margin.liquidation_slippage;
 
         for (agent_id, agent) in self.agents.iter_mut().enumerate() {
             let agent_id = agent_id as AgentId;
             let m_id = market.id();
+            agent.unfilled[m_id] = 0;
+
+            match &agent.kind {
+                AgentKind::Trader | AgentKind::Fundamentalist { trading: true } => {}
+                AgentKind::Fundamentalist { trading: false } => continue,
+                AgentKind::MarketMaker(mm) => {
+                    mm.quote(agent_id, agent, market);
+                    continue;
+                }
+                AgentKind::Arbitrageur(arb) => {
+                    if let Some(index) = &self.index {
+                        arb.trade(agent_id, agent, market, index);
+                    }
+                    continue;
+                }
+                AgentKind::Bankrupt => continue,
+            }
+
+            if agent.margin.as_ref().is_some_and(|m| m.is_liquidating()) {
+                liquidate(agent_id, agent, market, slippage);
+                continue;
+            }
 
             if rng.gen::<f32>() < agent.order_probability[m_id] {
-                if rng.gen::<f32>() < agent.state[m_id] {
-                    market.buy_order(agent_id, agent.cash * rng.gen::<f32>());
+                let belief = agent.strategy.belief(agent, &*market);
+                if rng.gen::<f32>() < belief {
+                    let cash = agent.buy_budget() * rng.gen::<f32>();
+                    let order_type = self.order_types.sample(rng);
+                    agent.commit_buy(cash);
+                    market.buy_order(agent_id, cash, belief, order_type);
                 } else {
-                    let assets = agent.assets[m_id] as f32 * rng.gen::<f32>();
-                    market.sell_order(agent_id, assets as u32)
+                    let price = market.price();
+                    let assets = agent.sell_budget(m_id, price) as f32 * rng.gen::<f32>();
+                    let order_type = self.order_types.sample(rng);
+                    agent.commit_sell(m_id, assets as u32, price);
+                    market.sell_order(agent_id, assets as u32, belief, order_type)
                 }
             }
         }
     }
 }
+
+/// The mean wealth of some agents, if there are any.
+fn mean_wealth<'a, Mk: Market, const M: usize>(
+    agents: impl Iterator<Item = &'a Agent<M>>,
+    markets: &[Mk],
+) -> Option<f32> {
+    let wealth = agents.map(|a| a.wealth(markets)).collect::<Vec<_>>();
+    Some(wealth.iter().sum::<f32>() / wealth.len() as f32).filter(|_| !wealth.is_empty())
+}
+
+/// Close all positions of an agent in a market, using orders that are likely
+/// to be filled.
+fn liquidate<Mk: Market, const M: usize>(
+    agent_id: AgentId,
+    agent: &Agent<M>,
+    market: &mut Mk,
+    slippage: f32,
+) {
+    let m_id = market.id();
+    let price = market.price();
+
+    let assets = agent.available_assets(m_id);
+    if assets > 0 {
+        market.limit_order(agent_id, Side::Sell, assets, price / (1.0 + slippage));
+    }
+    let short = agent.short(m_id);
+    if short > 0 {
+        market.limit_order(agent_id, Side::Buy, short, price * (1.0 + slippage));
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent::{AgentCollection, AgentId},
+    market::{Market, MarketId},
+};
+
+/// An invariant of the simulation that did not hold.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Invariant {
+    /// The total cash of all agents changed by more than the transfers and the
+    /// fees of the step explain.
+    Cash { expected: f64, actual: f64 },
+    /// The assets of a market held by all agents, minus the borrowed ones,
+    /// changed by more than the index shares created or redeemed and the
+    /// assets of new traders.
+    Assets { expected: i64, actual: i64 },
+    /// Agents spent more cash or sold more assets than they had.
+    Overdrawn,
+    /// Agents have a cash balance that is not a finite number.
+    NonFiniteCash,
+    /// The price of a market is not a positive finite number.
+    Price(f32),
+    /// The volatility of a market is not a finite number.
+    Volatility(f32),
+}
+
+impl Display for Invariant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Invariant::Cash { expected, actual } => {
+                write!(f, "total cash is {} instead of {}", actual, expected)
+            }
+            Invariant::Assets { expected, actual } => {
+                write!(f, "net assets are {} instead of {}", actual, expected)
+            }
+            Invariant::Overdrawn => write!(f, "agents spent more than they had"),
+            Invariant::NonFiniteCash => write!(f, "agents have a non-finite cash balance"),
+            Invariant::Price(price) => write!(f, "price is {}", price),
+            Invariant::Volatility(volatility) => write!(f, "volatility is {}", volatility),
+        }
+    }
+}
+
+/// A violated invariant with the step, market and agents involved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Violation {
+    pub step: usize,
+    pub market: Option<MarketId>,
+    pub agents: Vec<AgentId>,
+    pub invariant: Invariant,
+}
+
+/// Checks the accounting of a simulation after every step. Trades only move
+/// cash and assets between agents, so the totals may only change by the fees
+/// collected by the markets, the transfers of the agent collection and the
+/// assets it issued, see [`AgentCollection::issued`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Audit {
+    /// The total cash after the last check.
+    cash: f64,
+    /// The net assets per market after the last check.
+    net_assets: Vec<i64>,
+    violations: Vec<Violation>,
+}
+
+impl Audit {
+    pub fn new<Mk: Market, const M: usize>(agents: &AgentCollection<M>, markets: &[Mk]) -> Audit {
+        Audit {
+            cash: agents.total_cash(),
+            net_assets: markets.iter().map(|m| agents.net_assets(m.id())).collect(),
+            violations: Vec::new(),
+        }
+    }
+
+    fn violation(
+        &mut self,
+        step: usize,
+        market: Option<MarketId>,
+        agents: Vec<AgentId>,
+        invariant: Invariant,
+    ) {
+        self.violations.push(Violation {
+            step,
+            market,
+            agents,
+            invariant,
+        });
+    }
+
+    /// Record the agents that were overdrawn by the step of a market, see
+    /// [`AgentCollection::take_overdrawn`].
+    pub fn overdrawn(&mut self, step: usize, market: MarketId, agents: Vec<AgentId>) {
+        if !agents.is_empty() {
+            self.violation(step, Some(market), agents, Invariant::Overdrawn);
+        }
+    }
+
+    /// Check the invariants at the end of a step. Every violation is recorded
+    /// once, the totals of this step are the reference for the next one.
+    pub fn check<Mk: Market, const M: usize>(
+        &mut self,
+        step: usize,
+        agents: &AgentCollection<M>,
+        markets: &[Mk],
+    ) {
+        let fees = markets
+            .iter()
+            .filter_map(|m| m.revenue())
+            .map(|r| r.total() as f64)
+            .sum::<f64>();
+        let expected = self.cash + agents.transfers() - fees;
+        let actual = agents.total_cash();
+        // Balances are single precision, so every balance may be off by its
+        // rounding. The tolerance shrinks with the balances, so drift in small
+        // balances is still found.
+        let rounding = agents
+            .agents()
+            .iter()
+            .map(|a| a.cash.abs() as f64 * f32::EPSILON as f64)
+            .sum::<f64>();
+        if (actual - expected).abs() > rounding + 1e-9 * expected.abs().max(1.0) {
+            self.violation(step, None, Vec::new(), Invariant::Cash { expected, actual });
+        }
+        self.cash = actual;
+
+        let non_finite = agents
+            .agents()
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| !a.cash.is_finite())
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        if !non_finite.is_empty() {
+            self.violation(step, None, non_finite, Invariant::NonFiniteCash);
+        }
+
+        for m in markets {
+            let id = m.id();
+            let actual = agents.net_assets(id);
+            let expected = std::mem::replace(&mut self.net_assets[id], actual) + agents.issued(id);
+            if actual != expected {
+                self.violation(
+                    step,
+                    Some(id),
+                    Vec::new(),
+                    Invariant::Assets { expected, actual },
+                );
+            }
+
+            if !(m.price().is_finite() && m.price() > 0.0) {
+                self.violation(step, Some(id), Vec::new(), Invariant::Price(m.price()));
+            }
+            if !m.volatility().is_finite() {
+                let volatility = Invariant::Volatility(m.volatility());
+                self.violation(step, Some(id), Vec::new(), volatility);
+            }
+        }
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Write one line per violation, with the step, the market (`-` if it
+    /// concerns all markets), a comma separated list of agents (`-` if none)
+    /// and a description.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "step market agents violation")?;
+        for v in &self.violations {
+            let market = v.market.map_or_else(|| "-".to_string(), |m| m.to_string());
+            let agents = match v.agents.as_slice() {
+                [] => "-".to_string(),
+                agents => agents
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            };
+            writeln!(out, "{} {} {} {}", v.step, market, agents, v.invariant)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{config::Config, market::GenoaMarket, simulation::SimRng};
+
+    #[test]
+    fn test_detects_violations() {
+        let mut config = Config::default();
+        config.agent.agent_count = 3;
+        config.agent.fundamentalist_count = 0;
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let markets = vec![GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0))];
+        let mut audit = Audit::new(&agents, &markets);
+
+        agents.agent_mut(0).apply_buy(0, 2, 100.0);
+        agents.agent_mut(1).apply_sell(0, 2, 100.0);
+        agents.distribute_cash(30.0);
+        audit.check(0, &agents, &markets);
+        assert!(audit.violations().is_empty());
+
+        agents.step(&markets, 1);
+        agents.agent_mut(2).cash += 10.0;
+        agents.agent_mut(2).apply_sell(0, 1000, 1.0);
+        audit.overdrawn(1, 0, agents.take_overdrawn());
+        audit.check(1, &agents, &markets);
+        let invariants = audit
+            .violations()
+            .iter()
+            .map(|v| &v.invariant)
+            .collect::<Vec<_>>();
+        assert_eq!(invariants.len(), 3);
+        assert_eq!(audit.violations()[0].agents, [2]);
+        assert!(matches!(invariants[1], Invariant::Cash { .. }));
+        assert!(matches!(invariants[2], Invariant::Assets { .. }));
+    }
+
+    #[test]
+    fn test_detects_small_cash_drift() {
+        let mut config = Config::default();
+        config.agent.agent_count = 3;
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        for id in 0..3 {
+            agents.agent_mut(id).cash = 1.0;
+        }
+        let markets = vec![GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0))];
+        let mut audit = Audit::new(&agents, &markets);
+
+        agents.agent_mut(0).cash += 0.001;
+        audit.check(0, &agents, &markets);
+        assert_eq!(audit.violations().len(), 1);
+        assert!(matches!(
+            audit.violations()[0].invariant,
+            Invariant::Cash { .. }
+        ));
+    }
+}
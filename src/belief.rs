@@ -0,0 +1,79 @@
+use crate::config::{BeliefConfig, BeliefModel};
+
+/// The new belief of an agent about a market, from its own belief and the
+/// beliefs of its influencers, see [`BeliefConfig`]. Without influencers the
+/// belief stays the same.
+pub fn update(config: &BeliefConfig, belief: f32, influences: &[f32]) -> f32 {
+    if influences.is_empty() {
+        return belief;
+    }
+    let mean = |beliefs: &[f32]| beliefs.iter().sum::<f32>() / beliefs.len() as f32;
+
+    match config.model {
+        BeliefModel::Rounded => mean(influences).round(),
+        BeliefModel::DeGroot => {
+            config.self_weight * belief + (1.0 - config.self_weight) * mean(influences)
+        }
+        BeliefModel::BoundedConfidence => {
+            let heard = influences
+                .iter()
+                .copied()
+                .filter(|i| (i - belief).abs() <= config.confidence)
+                .collect::<Vec<_>>();
+            if heard.is_empty() {
+                belief
+            } else {
+                belief + config.convergence * (mean(&heard) - belief)
+            }
+        }
+        BeliefModel::Majority => {
+            let share = |side: fn(f32) -> bool| {
+                influences.iter().filter(|&&i| side(i)).count() as f32 / influences.len() as f32
+            };
+            if share(|i| i > 0.5) > config.threshold {
+                1.0
+            } else if share(|i| i < 0.5) > config.threshold {
+                0.0
+            } else {
+                belief
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_models() {
+        let config = |model| BeliefConfig {
+            model,
+            ..BeliefConfig::default()
+        };
+        let influences = [0.2, 0.9, 1.0];
+
+        let rounded = config(BeliefModel::Rounded);
+        assert_eq!(update(&rounded, 0.0, &influences), 1.0);
+        assert_eq!(update(&rounded, 0.0, &[0.0, 1.0, 0.0]), 0.0);
+        assert_eq!(update(&rounded, 0.3, &[]), 0.3);
+
+        let degroot = config(BeliefModel::DeGroot);
+        assert!((update(&degroot, 0.0, &influences) - 0.35).abs() < 1e-6);
+
+        // Only the influencer at 0.2 is close enough to be heard.
+        let bounded = config(BeliefModel::BoundedConfidence);
+        assert!((update(&bounded, 0.0, &influences) - 0.1).abs() < 1e-6);
+        assert_eq!(update(&bounded, 0.5, &[0.0, 1.0]), 0.5);
+
+        let majority = config(BeliefModel::Majority);
+        assert_eq!(update(&majority, 0.0, &influences), 1.0);
+        assert_eq!(update(&majority, 0.3, &[0.0, 1.0]), 0.3);
+        assert_eq!(update(&majority, 0.3, &[0.0, 0.5, 0.1]), 0.0);
+        let strict = BeliefConfig {
+            threshold: 0.7,
+            ..majority
+        };
+        assert_eq!(update(&strict, 0.3, &influences), 0.3);
+    }
+}
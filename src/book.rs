@@ -0,0 +1,684 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent::{AgentCollection, AgentId},
+    config::{Config, GridConfig, LimitPriceConfig},
+    depth::{depth_levels, DepthLevel},
+    fee::{Fees, Liquidity, Revenue},
+    fundamental::Fundamental,
+    market::{sample_limit_price, Market, MarketId, OrderType, Side},
+    simulation::SimRng,
+    tape::TradeTape,
+    volatility::VolatilityEstimator,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookOrder {
+    pub agent: AgentId,
+    pub side: Side,
+    pub asset_quantity: u32,
+    /// Infinite for market buy orders and zero for market sell orders.
+    pub limit_price: f32,
+    /// The order is removed from the book at the end of this step. Orders
+    /// that expire in the step they arrive in never rest in the book.
+    pub expires: usize,
+}
+
+impl BookOrder {
+    /// The limit price, `None` for market orders.
+    pub fn limit(&self) -> Option<f32> {
+        Some(self.limit_price).filter(|&p| p > 0.0 && p.is_finite())
+    }
+}
+
+/// A trade between an incoming order and an order resting in the book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill {
+    /// The agent that placed the resting order.
+    pub resting_agent: AgentId,
+    pub asset_quantity: u32,
+    /// Trades happen at the limit price of the resting order.
+    pub price: f32,
+}
+
+/// The resting orders of a market, sorted by price-time priority. This only
+/// does the bookkeeping of orders, agents are settled by [`OrderBookMarket`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OrderBook {
+    /// Buy orders, highest limit price first.
+    bids: VecDeque<BookOrder>,
+    /// Sell orders, lowest limit price first.
+    asks: VecDeque<BookOrder>,
+}
+
+impl OrderBook {
+    /// Match an incoming order against the resting orders of the other side.
+    /// The quantity of the order is reduced by the amount that was filled.
+    pub fn match_order(&mut self, order: &mut BookOrder) -> Vec<Fill> {
+        let mut fills = Vec::new();
+        let resting = match order.side {
+            Side::Buy => &mut self.asks,
+            Side::Sell => &mut self.bids,
+        };
+
+        while order.asset_quantity > 0 {
+            let best = match resting.front_mut() {
+                Some(best) => best,
+                None => break,
+            };
+            let crosses = match order.side {
+                Side::Buy => best.limit_price <= order.limit_price,
+                Side::Sell => best.limit_price >= order.limit_price,
+            };
+            if !crosses {
+                break;
+            }
+
+            let quantity = best.asset_quantity.min(order.asset_quantity);
+            fills.push(Fill {
+                resting_agent: best.agent,
+                asset_quantity: quantity,
+                price: best.limit_price,
+            });
+            best.asset_quantity -= quantity;
+            order.asset_quantity -= quantity;
+
+            if best.asset_quantity == 0 {
+                resting.pop_front();
+            }
+        }
+
+        fills
+    }
+
+    /// The amount of assets that can be bought with the cash by taking the
+    /// resting sell orders, given the gross price of an asset at each limit
+    /// price. There is no limit if the cash suffices for all of them.
+    pub fn affordable_asks(&self, mut cash: f32, gross_price: impl Fn(f32) -> f32) -> u32 {
+        let mut quantity = 0;
+        for ask in &self.asks {
+            let price = gross_price(ask.limit_price);
+            let affordable = ((cash / price) as u32).min(ask.asset_quantity);
+            quantity += affordable;
+            if affordable < ask.asset_quantity {
+                return quantity;
+            }
+            cash -= price * affordable as f32;
+        }
+        u32::MAX
+    }
+
+    /// Add an order to the book, behind all orders with the same or a better
+    /// price.
+    pub fn insert(&mut self, order: BookOrder) {
+        let limit = order.limit_price;
+        match order.side {
+            Side::Buy => {
+                let idx = self.bids.partition_point(|o| o.limit_price >= limit);
+                self.bids.insert(idx, order);
+            }
+            Side::Sell => {
+                let idx = self.asks.partition_point(|o| o.limit_price <= limit);
+                self.asks.insert(idx, order);
+            }
+        }
+    }
+
+    /// Remove all orders that expire at or before the given step.
+    pub fn remove_expired(&mut self, step: usize) -> Vec<BookOrder> {
+        self.remove_where(|o| o.expires <= step)
+    }
+
+    /// Remove all orders of an agent.
+    pub fn cancel(&mut self, agent: AgentId) -> Vec<BookOrder> {
+        self.remove_where(|o| o.agent == agent)
+    }
+
+    fn remove_where(&mut self, remove: impl Fn(&BookOrder) -> bool) -> Vec<BookOrder> {
+        let mut removed = Vec::new();
+        for side in [&mut self.bids, &mut self.asks] {
+            let (r, keep): (VecDeque<_>, VecDeque<_>) = side.drain(..).partition(|o| remove(o));
+            removed.extend(r);
+            *side = keep;
+        }
+        removed
+    }
+
+    pub fn best_bid(&self) -> Option<f32> {
+        self.bids.front().map(|o| o.limit_price)
+    }
+
+    pub fn best_ask(&self) -> Option<f32> {
+        self.asks.front().map(|o| o.limit_price)
+    }
+
+    pub fn spread(&self) -> Option<f32> {
+        Some(self.best_ask()? - self.best_bid()?)
+    }
+
+    pub fn bids(&self) -> &VecDeque<BookOrder> {
+        &self.bids
+    }
+
+    pub fn asks(&self) -> &VecDeque<BookOrder> {
+        &self.asks
+    }
+}
+
+/// A market with a persistent limit order book. Orders submitted during a step
+/// are matched in the order they arrived when [`Self::step`] is called, the
+/// unfilled remainder rests in the book until it is filled, cancelled or
+/// expires. While an order rests, the cash or assets needed to fill it are
+/// reserved at the agent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderBookMarket {
+    id: MarketId,
+    price_history: VecDeque<f32>,
+    price_history_count: usize,
+    volatility: f32,
+    estimator: VolatilityEstimator,
+    book: OrderBook,
+    /// Orders submitted this step, in the order they arrived.
+    incoming: Vec<BookOrder>,
+    /// Agents that want to cancel all their resting orders.
+    cancellations: Vec<AgentId>,
+    order_lifetime: usize,
+    limit_price: LimitPriceConfig,
+    /// The amount of assets traded during the last step.
+    volume: u32,
+    step: usize,
+    grid: GridConfig,
+    fees: Fees,
+    fundamental: Option<Fundamental>,
+    rng: SimRng,
+}
+
+impl OrderBookMarket {
+    /// Settle a trade between an incoming order and a resting one.
+    fn settle<const M: usize>(
+        &mut self,
+        agents: &mut AgentCollection<M>,
+        incoming: &BookOrder,
+        fill: &Fill,
+        tape: Option<&mut TradeTape>,
+    ) {
+        let (buyer, seller) = match incoming.side {
+            Side::Buy => (incoming.agent, fill.resting_agent),
+            Side::Sell => (fill.resting_agent, incoming.agent),
+        };
+        let resting_side = match incoming.side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+
+        agents.agent_mut(fill.resting_agent).release(
+            self.id,
+            resting_side,
+            fill.asset_quantity,
+            self.reserved_price(resting_side, fill.price),
+        );
+        agents
+            .agent_mut(buyer)
+            .apply_buy(self.id, fill.asset_quantity, fill.price);
+        agents
+            .agent_mut(seller)
+            .apply_sell(self.id, fill.asset_quantity, fill.price);
+
+        let value = fill.price * fill.asset_quantity as f32;
+        let resting = agents.agent_mut(fill.resting_agent);
+        self.fees.charge(resting, value, Liquidity::Maker);
+        let incoming = agents.agent_mut(incoming.agent);
+        self.fees.charge(incoming, value, Liquidity::Taker);
+
+        if let Some(tape) = tape {
+            tape.execution(self.id, buyer, Side::Buy, fill.asset_quantity, fill.price);
+            tape.execution(self.id, seller, Side::Sell, fill.asset_quantity, fill.price);
+        }
+    }
+
+    fn release<const M: usize>(&self, agents: &mut AgentCollection<M>, order: &BookOrder) {
+        agents.agent_mut(order.agent).release(
+            self.id,
+            order.side,
+            order.asset_quantity,
+            self.reserved_price(order.side, order.limit_price),
+        );
+    }
+
+    /// The price per asset that is reserved for a resting order, buyers also
+    /// reserve the fees and taxes they might pay.
+    fn reserved_price(&self, side: Side, limit_price: f32) -> f32 {
+        match side {
+            Side::Buy => self.fees.book_gross_price(limit_price),
+            Side::Sell => limit_price,
+        }
+    }
+
+    /// The step at the end of which an order placed now expires. Orders take
+    /// part in the step they are placed in, so an order with a lifetime of
+    /// `n` steps expires at the end of its `n`-th step.
+    fn expiry(&self, order_type: OrderType) -> usize {
+        match order_type {
+            OrderType::Limit => self.step + self.order_lifetime.saturating_sub(1),
+            OrderType::Market | OrderType::ImmediateOrCancel => self.step,
+            OrderType::GoodTill(steps) => self.step + steps.saturating_sub(1),
+        }
+    }
+
+    /// Add an incoming order, rounded to the grid of the market.
+    fn submit(&mut self, mut order: BookOrder) {
+        order.asset_quantity = self.grid.lots(order.asset_quantity);
+        order.limit_price = self.grid.limit_price(order.side, order.limit_price);
+        self.incoming.push(order);
+    }
+
+    fn record_price(&mut self, price: f32) {
+        self.price_history.push_front(price);
+        while self.price_history.len() > self.price_history_count {
+            self.price_history.pop_back();
+        }
+    }
+
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    /// The amount of assets traded during the last step.
+    pub fn volume(&self) -> u32 {
+        self.volume
+    }
+}
+
+impl Market for OrderBookMarket {
+    fn new(config: &Config, id: MarketId, mut rng: SimRng) -> OrderBookMarket {
+        OrderBookMarket {
+            id,
+            price_history: VecDeque::from(vec![config.market.initial_price; 3]),
+            price_history_count: config.market.price_history_count,
+            volatility: config.market.initial_volatility,
+            estimator: VolatilityEstimator::new(
+                &config.market.volatility,
+                config.market.initial_volatility,
+            ),
+            book: OrderBook::default(),
+            incoming: Vec::new(),
+            cancellations: Vec::new(),
+            order_lifetime: config.market.order_lifetime,
+            limit_price: config.market.limit_price.clone(),
+            volume: 0,
+            step: 0,
+            grid: config.market.grid(id),
+            fees: Fees::new(&config.market.fees(id)),
+            fundamental: Fundamental::from_config(config, id, &mut rng),
+            rng,
+        }
+    }
+
+    fn id(&self) -> MarketId {
+        self.id
+    }
+
+    fn price_history(&self) -> &VecDeque<f32> {
+        &self.price_history
+    }
+
+    fn volatility(&self) -> f32 {
+        self.volatility
+    }
+
+    fn jump_price(&mut self, size: f32) {
+        self.price_history[0] = self.grid.price(self.price_history[0] * (1.0 + size));
+    }
+
+    fn spread(&self) -> Option<f32> {
+        self.book.spread()
+    }
+
+    /// Market buy orders are sized with the current price, they are reduced
+    /// to what the agent can pay when they are matched.
+    fn buy_order(
+        &mut self,
+        agent: AgentId,
+        cash_quantity: f32,
+        belief: f32,
+        order_type: OrderType,
+    ) {
+        if cash_quantity < f32::EPSILON {
+            return;
+        }
+        let (limit_price, sizing_price) = match order_type {
+            OrderType::Market => (f32::INFINITY, self.price()),
+            _ => {
+                let limit_price = sample_limit_price(
+                    &self.limit_price,
+                    Side::Buy,
+                    self.price(),
+                    self.volatility,
+                    belief,
+                    &mut self.rng,
+                );
+                if limit_price <= 0.0 || !limit_price.is_finite() {
+                    return;
+                }
+                (limit_price, limit_price)
+            }
+        };
+        let asset_quantity = (cash_quantity / sizing_price) as u32;
+        self.submit(BookOrder {
+            agent,
+            side: Side::Buy,
+            asset_quantity,
+            limit_price,
+            expires: self.expiry(order_type),
+        });
+    }
+
+    fn sell_order(
+        &mut self,
+        agent: AgentId,
+        asset_quantity: u32,
+        belief: f32,
+        order_type: OrderType,
+    ) {
+        if asset_quantity == 0 {
+            return;
+        }
+        let limit_price = match order_type {
+            OrderType::Market => 0.0,
+            _ => {
+                let limit_price = sample_limit_price(
+                    &self.limit_price,
+                    Side::Sell,
+                    self.price(),
+                    self.volatility,
+                    belief,
+                    &mut self.rng,
+                );
+                if limit_price <= 0.0 || !limit_price.is_finite() {
+                    return;
+                }
+                limit_price
+            }
+        };
+        self.submit(BookOrder {
+            agent,
+            side: Side::Sell,
+            asset_quantity,
+            limit_price,
+            expires: self.expiry(order_type),
+        });
+    }
+
+    /// Cancel all resting orders of an agent, this happens before the
+    /// incoming orders are matched in the next step.
+    fn cancel_orders(&mut self, agent: AgentId) {
+        self.cancellations.push(agent);
+    }
+
+    fn revenue(&self) -> Option<Revenue> {
+        Some(self.fees.revenue()).filter(|_| self.fees.is_enabled())
+    }
+
+    fn fundamental(&self) -> Option<f32> {
+        self.fundamental.as_ref().map(Fundamental::value)
+    }
+
+    /// The depth of the resting orders, orders submitted during this step are
+    /// not included.
+    fn depth(&self) -> Vec<DepthLevel> {
+        let resting = |o: &BookOrder| (Some(o.limit_price), o.asset_quantity);
+        let mut levels = depth_levels(Side::Buy, self.book.bids.iter().map(resting));
+        levels.extend(depth_levels(Side::Sell, self.book.asks.iter().map(resting)));
+        levels
+    }
+
+    /// Submit an order with a given limit price, it is matched during the
+    /// next call to [`Self::step`].
+    fn limit_order(&mut self, agent: AgentId, side: Side, asset_quantity: u32, limit_price: f32) {
+        if limit_price <= 0.0 || !limit_price.is_finite() {
+            return;
+        }
+        self.submit(BookOrder {
+            agent,
+            side,
+            asset_quantity,
+            limit_price,
+            expires: self.expiry(OrderType::Limit),
+        });
+    }
+
+    /// Call this after all orders have been submitted, this matches the
+    /// incoming orders, removes expired orders and computes a new price and
+    /// volatility.
+    fn step<const M: usize>(
+        &mut self,
+        agents: &mut AgentCollection<M>,
+        mut tape: Option<&mut TradeTape>,
+    ) {
+        for agent in std::mem::take(&mut self.cancellations) {
+            for order in self.book.cancel(agent) {
+                self.release(agents, &order);
+            }
+        }
+
+        let mut last_trade = None;
+        self.volume = 0;
+
+        for mut order in std::mem::take(&mut self.incoming) {
+            // Agents can not place orders they are unable to pay for. Orders
+            // of agents with a margin account were already limited to their
+            // buying power when they were placed.
+            let agent = agents.agent(order.agent);
+            let affordable = match order.side {
+                _ if agent.margin.is_some() => order.asset_quantity,
+                Side::Buy if order.limit().is_none() => {
+                    self.book.affordable_asks(agent.available_cash(), |p| {
+                        self.fees.gross_price(p, Liquidity::Taker)
+                    })
+                }
+                Side::Buy => self.fees.affordable(
+                    agent.available_cash(),
+                    self.reserved_price(Side::Buy, order.limit_price),
+                ),
+                Side::Sell => agent.available_assets(self.id),
+            };
+            order.asset_quantity = self.grid.lots(order.asset_quantity.min(affordable));
+            if order.asset_quantity == 0 {
+                continue;
+            }
+
+            if let Some(tape) = tape.as_deref_mut() {
+                tape.order(
+                    self.id,
+                    order.agent,
+                    order.side,
+                    order.asset_quantity,
+                    order.limit(),
+                );
+            }
+
+            for fill in self.book.match_order(&mut order) {
+                self.settle(agents, &order, &fill, tape.as_deref_mut());
+                last_trade = Some(fill.price);
+                self.volume += fill.asset_quantity;
+            }
+
+            if order.asset_quantity > 0 {
+                agents
+                    .agent_mut(order.agent)
+                    .add_unfilled(self.id, order.asset_quantity);
+            }
+            if order.asset_quantity > 0 && order.expires > self.step {
+                agents.agent_mut(order.agent).reserve(
+                    self.id,
+                    order.side,
+                    order.asset_quantity,
+                    self.reserved_price(order.side, order.limit_price),
+                );
+                self.book.insert(order);
+            }
+        }
+
+        for order in self.book.remove_expired(self.step) {
+            self.release(agents, &order);
+        }
+        self.fees.end_step(agents);
+        if let Some(fundamental) = &mut self.fundamental {
+            fundamental.step();
+        }
+
+        let price = last_trade.unwrap_or_else(|| self.price());
+        if let Some(tape) = tape {
+            tape.book_step(self.id, price, self.volume);
+        }
+        self.record_price(price);
+        if let Some(volatility) = self.estimator.update(&self.price_history) {
+            self.volatility = volatility;
+        }
+
+        self.step += 1;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn order(agent: AgentId, side: Side, asset_quantity: u32, limit_price: f32) -> BookOrder {
+        BookOrder {
+            agent,
+            side,
+            asset_quantity,
+            limit_price,
+            expires: 10,
+        }
+    }
+
+    #[test]
+    fn test_price_time_priority() {
+        let mut book = OrderBook::default();
+        book.insert(order(0, Side::Sell, 5, 1.1));
+        book.insert(order(1, Side::Sell, 5, 1.0));
+        book.insert(order(2, Side::Sell, 5, 1.0));
+        book.insert(order(3, Side::Buy, 5, 0.9));
+
+        let mut incoming = order(4, Side::Buy, 12, 1.05);
+        let fills = book.match_order(&mut incoming);
+        let filled = fills
+            .iter()
+            .map(|f| (f.resting_agent, f.asset_quantity))
+            .collect::<Vec<_>>();
+        assert_eq!(filled, [(1, 5), (2, 5)]);
+        assert_eq!(incoming.asset_quantity, 2);
+        assert_eq!(book.spread(), Some(1.1 - 0.9));
+
+        assert_eq!(book.cancel(0).len(), 1);
+        assert_eq!(book.remove_expired(10).len(), 1);
+        assert_eq!(book.best_ask(), None);
+        assert_eq!(book.best_bid(), None);
+    }
+
+    #[test]
+    fn test_orders_rest_and_reserve() {
+        let mut config = Config::default();
+        config.agent.agent_count = 3;
+        config.market.order_lifetime = 2;
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let mut market = OrderBookMarket::new(&config, 0, SimRng::seed_from_u64(0));
+        let (cash, assets) = (agents.agent(0).cash, agents.agent(1).assets[0]);
+
+        market.limit_order(0, Side::Buy, 10, 100.0);
+        market.step(&mut agents, None);
+        assert_eq!(agents.agent(0).reserved_cash, 1000.0);
+        assert_eq!(market.book().best_bid(), Some(100.0));
+
+        // The remaining order expires at the end of its second step.
+        market.limit_order(1, Side::Sell, 4, 99.0);
+        market.step(&mut agents, None);
+        assert_eq!(market.price(), 100.0);
+        assert_eq!(market.volume(), 4);
+        assert_eq!(agents.agent(0).cash, cash - 400.0);
+        assert_eq!(agents.agent(1).assets[0], assets - 4);
+        assert_eq!(market.book().best_bid(), None);
+        assert_eq!(agents.agent(0).reserved_cash, 0.0);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut config = Config::default();
+        config.market.order_lifetime = 3;
+        let mut market = OrderBookMarket::new(&config, 0, SimRng::seed_from_u64(0));
+        market.step = 5;
+        assert_eq!(market.expiry(OrderType::Limit), 7);
+        assert_eq!(market.expiry(OrderType::GoodTill(3)), 7);
+        assert_eq!(market.expiry(OrderType::GoodTill(1)), 5);
+        assert_eq!(market.expiry(OrderType::Market), 5);
+        assert_eq!(market.expiry(OrderType::ImmediateOrCancel), 5);
+    }
+
+    #[test]
+    fn test_orders_that_do_not_rest() {
+        let mut config = Config::default();
+        config.agent.agent_count = 2;
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let mut market = OrderBookMarket::new(&config, 0, SimRng::seed_from_u64(0));
+        let assets = agents.agent(0).assets[0];
+
+        market.limit_order(1, Side::Sell, 4, 99.0);
+        market.step(&mut agents, None);
+        market.buy_order(0, 1000.0, 0.5, OrderType::Market);
+        market.step(&mut agents, None);
+        assert_eq!(market.price(), 99.0);
+        assert_eq!(agents.agent(0).assets[0], assets + 4);
+        assert_eq!(agents.agent(0).unfilled[0], 6);
+        assert_eq!(market.book().best_bid(), None);
+        assert_eq!(agents.agent(0).reserved_cash, 0.0);
+
+        market.sell_order(1, 5, 0.5, OrderType::ImmediateOrCancel);
+        market.step(&mut agents, None);
+        assert_eq!(market.book().best_ask(), None);
+        assert_eq!(agents.agent(1).reserved_assets[0], 0);
+
+        // An order good for one step only takes part in the step it is placed.
+        market.sell_order(1, 5, 0.5, OrderType::GoodTill(1));
+        market.step(&mut agents, None);
+        assert_eq!(market.book().best_ask(), None);
+        assert_eq!(agents.agent(1).reserved_assets[0], 0);
+
+        market.sell_order(1, 5, 0.5, OrderType::GoodTill(2));
+        market.step(&mut agents, None);
+        assert!(market.book().best_ask().is_some());
+        assert_eq!(agents.agent(1).reserved_assets[0], 5);
+        market.step(&mut agents, None);
+        assert_eq!(market.book().best_ask(), None);
+        assert_eq!(agents.agent(1).reserved_assets[0], 0);
+    }
+
+    #[test]
+    fn test_margin_buys_in_book() {
+        let mut config = Config::default();
+        config.agent.agent_count = 2;
+        let buy_with = |config: &Config| {
+            let mut agents = AgentCollection::<5>::new(config, SimRng::seed_from_u64(0));
+            let mut markets = vec![OrderBookMarket::new(config, 0, SimRng::seed_from_u64(0))];
+            agents.agent_mut(0).cash = 1000.0;
+            agents.step(&markets, 0);
+            let assets = agents.agent(0).assets[0];
+
+            markets[0].limit_order(1, Side::Sell, 20, 100.0);
+            markets[0].step(&mut agents, None);
+            markets[0].limit_order(0, Side::Buy, 20, 100.0);
+            markets[0].step(&mut agents, None);
+            (agents.agent(0).assets[0] - assets, agents.agent(0).cash)
+        };
+
+        // Without a margin account the order is cut to the cash of the agent.
+        assert_eq!(buy_with(&config), (10, 0.0));
+        config.agent.margin.enabled = true;
+        assert_eq!(buy_with(&config), (20, -1000.0));
+    }
+}
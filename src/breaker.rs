@@ -0,0 +1,133 @@
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::CircuitBreakerConfig;
+
+/// The rule that caused a halt, with the relative price change that violated
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Trigger {
+    Move(f32),
+    Drawdown(f32),
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Move(m) => write!(f, "price moved by {:.2}%", m * 100.0),
+            Trigger::Drawdown(d) => write!(f, "drawdown of {:.2}%", d * 100.0),
+        }
+    }
+}
+
+/// Decides whether the clearing of a step may be executed. Once a rule is
+/// violated, the clearing is discarded and trading halts for a number of
+/// steps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitBreaker {
+    max_move: Option<f32>,
+    max_drawdown: Option<f32>,
+    halt_steps: usize,
+    /// Halted steps left after the current one.
+    remaining: usize,
+    /// The highest price since the start or the last halt.
+    peak: f32,
+    /// Whether the last step was halted.
+    halted: bool,
+    /// The rule violated in the last step.
+    trigger: Option<Trigger>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig, initial_price: f32) -> CircuitBreaker {
+        CircuitBreaker {
+            max_move: config.max_move,
+            max_drawdown: config.max_drawdown,
+            halt_steps: config.halt_steps,
+            remaining: 0,
+            peak: initial_price,
+            halted: false,
+            trigger: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_move.is_some() || self.max_drawdown.is_some()
+    }
+
+    /// Checks the clearing price of a step, this has to be called exactly once
+    /// per step. Returns false if trading is halted, in which case the
+    /// clearing must be discarded.
+    pub fn allows_trading(&mut self, last_price: f32, price: f32) -> bool {
+        self.trigger = None;
+
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.halted = true;
+            return false;
+        }
+
+        let change = price / last_price - 1.0;
+        let drawdown = 1.0 - price / self.peak;
+        self.trigger = match (self.max_move, self.max_drawdown) {
+            (Some(max), _) if change.abs() > max => Some(Trigger::Move(change)),
+            (_, Some(max)) if drawdown > max => Some(Trigger::Drawdown(drawdown)),
+            _ => None,
+        };
+
+        self.halted = self.trigger.is_some();
+        if self.halted {
+            self.remaining = self.halt_steps;
+            self.peak = last_price;
+        } else {
+            self.peak = self.peak.max(price);
+        }
+        !self.halted
+    }
+
+    /// Whether trading was halted during the last step.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// The rule that was violated during the last step, starting a new halt.
+    pub fn trigger(&self) -> Option<Trigger> {
+        self.trigger
+    }
+
+    pub fn halt_steps(&self) -> usize {
+        self.halt_steps
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_halts() {
+        let mut breaker = CircuitBreaker::new(
+            &CircuitBreakerConfig {
+                max_move: Some(0.1),
+                max_drawdown: Some(0.15),
+                halt_steps: 2,
+            },
+            100.0,
+        );
+
+        assert!(breaker.allows_trading(100.0, 109.0));
+        assert!(!breaker.allows_trading(109.0, 80.0));
+        assert!(matches!(breaker.trigger(), Some(Trigger::Move(_))));
+        assert!(!breaker.allows_trading(109.0, 109.0));
+        assert!(!breaker.allows_trading(109.0, 109.0));
+        assert_eq!(breaker.trigger(), None);
+        assert!(breaker.is_halted());
+
+        // The peak is reset to the price before the halt.
+        assert!(breaker.allows_trading(109.0, 100.0));
+        assert!(breaker.allows_trading(100.0, 94.0));
+        assert!(!breaker.allows_trading(94.0, 90.0));
+        assert!(matches!(breaker.trigger(), Some(Trigger::Drawdown(_))));
+    }
+}
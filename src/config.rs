@@ -1,12 +1,28 @@
-use std::{error::Error, path::Path};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    path::Path,
+};
 
-use rand::{prelude::ThreadRng, Rng};
+use rand::Rng;
 use rand_distr::{Distribution as RDist, Normal, Uniform};
 use serde::{Deserialize, Serialize};
 use toml::from_str;
 
+use crate::market::{MarketId, OrderType, Side};
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct Config {
+    /// Seed for the random number generator. When this is not set, and no seed
+    /// is given on the command line, a random seed is chosen.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Shocks to the beliefs of the agents and the prices of the markets.
+    #[serde(default)]
+    pub news: Vec<NewsConfig>,
+    /// The risk-free rate paid on cash.
+    pub interest: InterestConfig,
     pub market: MarketConfig,
     pub agent: AgentConfig,
 }
@@ -14,16 +30,110 @@ pub struct Config {
 impl Config {
     pub fn load(path: impl AsRef<Path>) -> Result<Config, Box<dyn Error>> {
         let config = std::fs::read_to_string(path)?;
-        Ok(from_str(&config)?)
+        let config: Config = from_str(&config)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check references between the parts of the config, like the markets
+    /// named by the news, which can not be checked while parsing.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let market_count = self.market.market_count;
+        for &m in self.news.iter().flat_map(|news| &news.markets) {
+            if m >= market_count {
+                return Err(format!("the news market {} does not exist", m).into());
+            }
+        }
+
+        let index = &self.market.index;
+        if let Some(market) = index.market {
+            if market >= market_count {
+                return Err(format!("the index market {} does not exist", market).into());
+            }
+            for &m in &index.components {
+                if m >= market_count || m == market {
+                    return Err(format!("the index can't contain market {}", m).into());
+                }
+            }
+            if index.components.is_empty() && market_count < 2 {
+                return Err("the index needs at least one component".into());
+            }
+            if index.units.contains(&0) {
+                return Err("every component of the index needs at least one asset".into());
+            }
+        }
+
+        let strategy = self.agent.fundamentalists.strategy.strategy;
+        if strategy != StrategyKind::Fundamentalist {
+            return Err(format!(
+                "fundamentalists can't trade with the {:?} strategy",
+                strategy
+            )
+            .into());
+        }
+        Ok(())
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct MarketConfig {
     pub market_count: usize,
     pub initial_price: f32,
     pub initial_volatility: f32,
     pub price_history_count: usize,
+
+    /// The mechanism used by each market, indexed by market id. Markets without
+    /// an entry use the Genoa call auction.
+    pub mechanisms: Vec<MarketMechanism>,
+
+    /// The amount of steps an order rests in an order book, before it expires.
+    pub order_lifetime: usize,
+
+    /// How the executed assets of a call auction are divided between the
+    /// orders.
+    pub allocation: Allocation,
+
+    /// The fees and taxes of each market, indexed by market id. Markets
+    /// without an entry are free of charge.
+    pub fees: Vec<FeeConfig>,
+
+    /// The tick and lot size of each market, indexed by market id. Markets
+    /// without an entry accept any price and quantity.
+    pub grids: Vec<GridConfig>,
+
+    /// How agents choose the limit price of their orders.
+    pub limit_price: LimitPriceConfig,
+
+    /// How the volatility of a market is estimated from its prices.
+    pub volatility: VolatilityConfig,
+
+    /// Rules that halt trading in call auction markets after large price
+    /// movements.
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// The intrinsic value of the asset of every market.
+    pub fundamental: FundamentalConfig,
+
+    /// A market whose shares are baskets of the assets of other markets.
+    pub index: IndexConfig,
+}
+
+impl MarketConfig {
+    pub fn mechanism(&self, market: MarketId) -> MarketMechanism {
+        self.mechanisms
+            .get(market)
+            .copied()
+            .unwrap_or(MarketMechanism::Genoa)
+    }
+
+    pub fn fees(&self, market: MarketId) -> FeeConfig {
+        self.fees.get(market).cloned().unwrap_or_default()
+    }
+
+    pub fn grid(&self, market: MarketId) -> GridConfig {
+        self.grids.get(market).cloned().unwrap_or_default()
+    }
 }
 
 impl Default for MarketConfig {
@@ -33,18 +143,417 @@ impl Default for MarketConfig {
             initial_price: 100.0,
             initial_volatility: 0.003,
             price_history_count: 20,
+            mechanisms: Vec::new(),
+            order_lifetime: 10,
+            allocation: Allocation::PricePriority,
+            fees: Vec::new(),
+            grids: Vec::new(),
+            limit_price: LimitPriceConfig::default(),
+            volatility: VolatilityConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            fundamental: FundamentalConfig::default(),
+            index: IndexConfig::default(),
+        }
+    }
+}
+
+/// An index market, whose shares are created from baskets of assets of the
+/// component markets and can be redeemed for them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct IndexConfig {
+    /// The market of the index, there is no index if not set.
+    pub market: Option<MarketId>,
+
+    /// The markets in the basket, all other markets if empty.
+    pub components: Vec<MarketId>,
+
+    /// The assets of each component in the basket, in the order of the
+    /// components. Components without an entry contribute one asset.
+    pub units: Vec<u32>,
+
+    /// The amount of index shares created from one basket. By default the
+    /// amount of assets in the basket, so the value of a share starts at the
+    /// initial price.
+    pub creation_unit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarketMechanism {
+    /// A call auction that clears all orders of a step at a single price, and
+    /// discards unfilled orders.
+    Genoa,
+    /// A limit order book with price-time priority, where orders are matched
+    /// as they arrive and rest across steps until they expire.
+    OrderBook,
+}
+
+/// Buyers pay the price times a random factor, sellers accept the price
+/// divided by it. How the factor is sampled depends on the model.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitPriceConfig {
+    pub model: LimitPriceModel,
+
+    /// The mean of the factor.
+    pub mean: f32,
+
+    /// The spread of the factor, in multiples of the volatility of the market.
+    pub volatility_factor: f32,
+
+    /// Degrees of freedom of the Student-t model, smaller values produce
+    /// fatter tails.
+    pub degrees_of_freedom: f32,
+}
+
+impl Default for LimitPriceConfig {
+    fn default() -> Self {
+        Self {
+            model: LimitPriceModel::Genoa,
+            mean: 1.01,
+            volatility_factor: 3.5,
+            degrees_of_freedom: 3.0,
+        }
+    }
+}
+
+impl Display for LimitPriceConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} mean={} volatility_factor={}",
+            self.model, self.mean, self.volatility_factor
+        )?;
+        if self.model == LimitPriceModel::StudentT {
+            write!(f, " degrees_of_freedom={}", self.degrees_of_freedom)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPriceModel {
+    /// The factor is normal distributed, as in the Genoa market paper.
+    Genoa,
+    /// Like `Genoa`, but the distance of the mean from one scales with the
+    /// belief of the agent in its order. An agent with a state of one buys
+    /// with the full offset, an undecided agent without any.
+    Belief,
+    /// The factor follows a scaled Student-t distribution around the mean.
+    /// Its heavy tails are cut off at a small positive factor, so limit
+    /// prices stay positive.
+    StudentT,
+}
+
+/// The fundamental value follows a geometric random walk, which reverts to a
+/// mean value if `mean_reversion` is positive. Every step the log of the value
+/// changes by `drift + mean_reversion * (ln(mean) - ln(value)) + volatility * e`
+/// where `e` is standard normal.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FundamentalConfig {
+    /// Without this markets have no fundamental value.
+    pub enabled: bool,
+
+    /// The value at the start, the initial price of the market if not set.
+    pub initial_value: Option<f32>,
+
+    /// Change of the log value per step.
+    pub drift: f32,
+
+    /// Standard deviation of the change of the log value per step.
+    pub volatility: f32,
+
+    /// The fraction of the distance to the mean, in log space, that is closed
+    /// every step.
+    pub mean_reversion: f32,
+
+    /// The value the process reverts to, the initial value if not set.
+    pub mean: Option<f32>,
+
+    /// Sudden changes of the value at given steps.
+    pub jumps: Vec<FundamentalJump>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FundamentalJump {
+    pub step: usize,
+    /// The market whose value jumps, all markets if not set.
+    pub market: Option<MarketId>,
+    /// The relative change of the value, -0.2 drops it by 20%.
+    pub size: f32,
+}
+
+/// A shock that happens either at a given step, or at random steps as a
+/// Poisson process. It can change the beliefs of agents, flip fundamentalists
+/// and move prices, any combination of these.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NewsConfig {
+    /// The step of the shock.
+    pub step: Option<usize>,
+
+    /// The expected amount of shocks per step, used when no step is given.
+    pub rate: f32,
+
+    /// Describes the shock in the events.
+    pub description: String,
+
+    /// The markets affected by the shock, all markets if empty.
+    pub markets: Vec<MarketId>,
+
+    /// The fraction of the traders whose state is shifted.
+    pub agent_fraction: f32,
+
+    /// The change of the state of the chosen traders, clamped to the range 0
+    /// to 1. A shift of 1 makes them all optimistic, -1 pessimistic.
+    pub state_shift: f32,
+
+    /// The fraction of the fundamentalists whose state is flipped.
+    pub fundamentalist_fraction: f32,
+
+    /// The relative change of the price, -0.2 drops it by 20%.
+    pub price_jump: f32,
+}
+
+impl Default for NewsConfig {
+    fn default() -> Self {
+        Self {
+            step: None,
+            rate: 0.0,
+            description: "news".to_string(),
+            markets: Vec::new(),
+            agent_fraction: 0.0,
+            state_shift: 0.0,
+            fundamentalist_fraction: 0.0,
+            price_jump: 0.0,
+        }
+    }
+}
+
+/// The risk-free rate, which accrues to the cash of all agents every step.
+/// Agents with a margin account that borrowed cash pay the rate plus a spread
+/// on it instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct InterestConfig {
+    /// The rate per step until the first change of the schedule.
+    pub rate: f32,
+
+    /// The rate charged for cash borrowed on margin on top of the risk-free
+    /// rate.
+    pub borrow_spread: f32,
+
+    /// Changes of the rate, each one applies from its step on.
+    pub schedule: Vec<RateChange>,
+}
+
+impl InterestConfig {
+    /// The risk-free rate at a step.
+    pub fn rate_at(&self, step: usize) -> f32 {
+        self.schedule
+            .iter()
+            .filter(|c| c.step <= step)
+            .max_by_key(|c| c.step)
+            .map_or(self.rate, |c| c.rate)
+    }
+
+    /// Whether any interest is paid or charged.
+    pub fn is_enabled(&self) -> bool {
+        self.rate != 0.0 || self.borrow_spread != 0.0 || !self.schedule.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateChange {
+    pub step: usize,
+    pub rate: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// The largest relative price change allowed in a single step, compared
+    /// to the price of the previous step.
+    pub max_move: Option<f32>,
+
+    /// The largest relative drop of the price below its highest value since
+    /// the start, or since the last halt.
+    pub max_drawdown: Option<f32>,
+
+    /// The amount of steps trading is halted after a rule was violated. The
+    /// step violating the rule is always discarded.
+    pub halt_steps: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            max_move: None,
+            max_drawdown: None,
+            halt_steps: 5,
+        }
+    }
+}
+
+/// The estimator of the volatility, it is updated with the log return of
+/// every step.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VolatilityConfig {
+    pub model: VolatilityModel,
+
+    /// The weight of the previous variance in the `Ewma` model.
+    pub decay: f32,
+
+    /// The constant term of the `Garch` model. By default it is chosen so the
+    /// long run variance is the square of the initial volatility.
+    pub omega: Option<f32>,
+
+    /// The weight of the last squared log return in the `Garch` model.
+    pub alpha: f32,
+
+    /// The weight of the previous variance in the `Garch` model.
+    pub beta: f32,
+}
+
+impl Default for VolatilityConfig {
+    fn default() -> Self {
+        Self {
+            model: VolatilityModel::Rolling,
+            decay: 0.94,
+            omega: None,
+            alpha: 0.1,
+            beta: 0.85,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolatilityModel {
+    /// The sample standard deviation of the log returns over the price
+    /// history.
+    Rolling,
+    /// An exponentially weighted moving average of the squared log returns.
+    Ewma,
+    /// The GARCH(1,1) recursion.
+    Garch,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// Orders with better limit prices are filled first, orders with the same
+    /// limit price in the order they arrived. Only the last order is filled
+    /// partially.
+    PricePriority,
+    /// Like `PricePriority`, but the orders at the marginal limit price share
+    /// the remaining assets in proportion to their size.
+    ProRata,
+    /// Like `PricePriority`, but orders with the same limit price are filled
+    /// in random order.
+    RandomTieBreak,
+}
+
+/// The charges on every trade of a market, both the buyer and the seller pay
+/// them on top of the value of the trade.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FeeConfig {
+    /// Fee charged per trade, independent of its value.
+    pub fixed: f32,
+
+    /// Fee charged relative to the value of a trade.
+    pub proportional: f32,
+
+    /// Additional proportional fee for the resting side of a trade in an order
+    /// book.
+    pub maker: f32,
+
+    /// Additional proportional fee for the incoming side of a trade in an order
+    /// book.
+    pub taker: f32,
+
+    /// Transaction tax relative to the value of a trade.
+    pub tax: f32,
+
+    /// Pay out the revenue of every step in equal parts to all agents.
+    pub redistribute: bool,
+}
+
+/// The prices and quantities a market accepts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GridConfig {
+    /// Prices are multiples of the tick size, any price is allowed if not
+    /// set.
+    pub tick_size: Option<f32>,
+
+    /// Order quantities are multiples of the lot size.
+    pub lot_size: u32,
+}
+
+impl Default for GridConfig {
+    fn default() -> Self {
+        Self {
+            tick_size: None,
+            lot_size: 1,
+        }
+    }
+}
+
+impl GridConfig {
+    /// The price in ticks, prices within a thousandth of a tick of the grid
+    /// are taken to be on it.
+    fn ticks(price: f32, tick_size: f32) -> f32 {
+        let ticks = price / tick_size;
+        if (ticks - ticks.round()).abs() < 1e-3 {
+            ticks.round()
+        } else {
+            ticks
+        }
+    }
+
+    /// Round a limit price to the grid, buy limits down and sell limits up,
+    /// so orders never trade at a worse price than intended. Buy limits are
+    /// at least one tick. Market orders keep their infinite or zero limit.
+    pub fn limit_price(&self, side: Side, price: f32) -> f32 {
+        match self.tick_size {
+            Some(tick) if price.is_finite() && price > 0.0 => match side {
+                Side::Buy => Self::ticks(price, tick).floor().max(1.0) * tick,
+                Side::Sell => Self::ticks(price, tick).ceil() * tick,
+            },
+            _ => price,
+        }
+    }
+
+    /// Round a price to the nearest point of the grid.
+    pub fn price(&self, price: f32) -> f32 {
+        match self.tick_size {
+            Some(tick) => Self::ticks(price, tick).round() * tick,
+            None => price,
         }
     }
+
+    /// Round a quantity down to whole lots.
+    pub fn lots(&self, quantity: u32) -> u32 {
+        quantity - quantity % self.lot_size.max(1)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct AgentConfig {
-    /// Total amount of fundamentalists in the simulation.
+    /// Total amount of fundamentalists in the simulation, see
+    /// [`FundamentalistConfig`].
     pub fundamentalist_count: usize,
 
     /// Total amount of agents in the simulation.
     pub agent_count: usize,
 
+    /// The trading strategies of the agents, the agents not covered by any of
+    /// them are noise traders.
+    pub strategies: Vec<StrategyConfig>,
+
     /// The amount of influencers that influence the agent every step, they will
     /// be influenced.
     pub influencers_count: Distribution,
@@ -85,6 +594,31 @@ pub struct AgentConfig {
     /// The initial belief the agent has about each market. Zero is bad, one is
     /// good.
     pub initial_state: Distribution,
+
+    /// How agents update their beliefs from the beliefs of their influencers.
+    pub beliefs: BeliefConfig,
+
+    /// Agents that provide liquidity, by posting buy and sell orders around the
+    /// current price.
+    pub market_makers: MarketMakerConfig,
+
+    /// Allows agents to borrow cash and assets.
+    pub margin: MarginConfig,
+
+    /// The types of the orders placed by agents that trade on their beliefs.
+    pub order_types: OrderTypeConfig,
+
+    /// Agents that trade the index against its components, and create or
+    /// redeem index shares, see [`IndexConfig`].
+    pub arbitrageurs: ArbitrageurConfig,
+
+    /// Agents with a fixed belief about every market, that influences other
+    /// agents. They only trade on their estimate of the value if enabled.
+    pub fundamentalists: FundamentalistConfig,
+
+    /// Traders that go bankrupt and leave, and new traders that enter during
+    /// the simulation.
+    pub population: PopulationConfig,
 }
 
 impl Default for AgentConfig {
@@ -92,16 +626,370 @@ impl Default for AgentConfig {
         Self {
             agent_count: 1000,
             fundamentalist_count: 100,
+            strategies: Vec::new(),
             initial_assets: Distribution::static_value(30.0),
             order_probability: Distribution::static_value(1.0),
             influence_probability: Distribution::static_value(0.8),
             influencers_count: Distribution::static_value(1.0),
             initial_cash: Distribution::static_value(3000.0),
             initial_state: Distribution::Bernoulli { p: 0.5 },
+            beliefs: BeliefConfig::default(),
             reflection_delay: Distribution::static_value(10.0),
             friend_threshold: Distribution::static_value(0.6),
             max_friends: Distribution::static_value(0.0),
             friend_influence_probability: Distribution::static_value(0.4),
+            market_makers: MarketMakerConfig::default(),
+            margin: MarginConfig::default(),
+            order_types: OrderTypeConfig::default(),
+            arbitrageurs: ArbitrageurConfig::default(),
+            fundamentalists: FundamentalistConfig::default(),
+            population: PopulationConfig::default(),
+        }
+    }
+}
+
+/// The probabilities with which an order is of a certain type, all other
+/// orders are limit orders with the usual lifetime of the market.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OrderTypeConfig {
+    /// Probability of a market order, which is executed at any price.
+    pub market: f32,
+
+    /// Probability of a limit order whose unfilled remainder is discarded
+    /// right away.
+    pub immediate_or_cancel: f32,
+
+    /// Probability of a limit order that takes part in trading for
+    /// `good_till_steps` steps.
+    pub good_till: f32,
+
+    pub good_till_steps: usize,
+}
+
+impl OrderTypeConfig {
+    /// Draw the type of a new order, the random number generator is only used
+    /// if any type other than a limit order is configured.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> OrderType {
+        let types = [
+            (self.market, OrderType::Market),
+            (self.immediate_or_cancel, OrderType::ImmediateOrCancel),
+            (self.good_till, OrderType::GoodTill(self.good_till_steps)),
+        ];
+        if types.iter().all(|&(p, _)| p <= 0.0) {
+            return OrderType::Limit;
+        }
+
+        let mut r = rng.gen::<f32>();
+        for (p, order_type) in types {
+            if r < p {
+                return order_type;
+            }
+            r -= p;
+        }
+        OrderType::Limit
+    }
+}
+
+impl Default for OrderTypeConfig {
+    fn default() -> Self {
+        Self {
+            market: 0.0,
+            immediate_or_cancel: 0.0,
+            good_till: 0.0,
+            good_till_steps: 5,
+        }
+    }
+}
+
+/// Margin accounts of the agents that trade on their beliefs. Positions are
+/// valued at the current price of each market.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MarginConfig {
+    /// Give agents a margin account, without it agents can not hold negative
+    /// cash or sell assets they do not own.
+    pub enabled: bool,
+
+    /// The largest ratio between the value of all positions, long and short,
+    /// and the equity of an agent, up to which new orders can be placed.
+    pub max_leverage: f32,
+
+    /// Allow selling borrowed assets.
+    pub allow_short: bool,
+
+    /// The smallest ratio between the equity and the value of all positions.
+    /// Below it, all positions of the agent are closed.
+    pub maintenance_margin: f32,
+
+    /// Fee per step relative to the value of the borrowed assets.
+    pub borrow_fee: f32,
+
+    /// How far the limit price of liquidation orders is from the current
+    /// price, relative to it.
+    pub liquidation_slippage: f32,
+}
+
+impl Default for MarginConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_leverage: 2.0,
+            allow_short: true,
+            maintenance_margin: 0.25,
+            borrow_fee: 0.0001,
+            liquidation_slippage: 0.05,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MarketMakerConfig {
+    /// Amount of market makers in the simulation, they do not gossip and are
+    /// not influenced.
+    pub count: usize,
+
+    /// The smallest distance between the price and the orders of a market
+    /// maker, relative to the price.
+    pub min_spread: f32,
+
+    /// The distance between the price and the orders of a market maker, in
+    /// multiples of the volatility of the market.
+    pub spread: Distribution,
+
+    /// The amount of assets a market maker is willing to hold above or below
+    /// its initial assets. When the volatility rises above the initial
+    /// volatility of the market, the limit shrinks accordingly.
+    pub inventory_limit: Distribution,
+
+    /// The amount of assets in each order.
+    pub order_size: Distribution,
+
+    /// Initial amount of cash a market maker holds.
+    pub initial_cash: Distribution,
+
+    /// Initial amount of assets a market maker holds in every market.
+    pub initial_assets: Distribution,
+}
+
+impl Default for MarketMakerConfig {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            min_spread: 0.001,
+            spread: Distribution::static_value(2.0),
+            inventory_limit: Distribution::static_value(100.0),
+            order_size: Distribution::static_value(10.0),
+            initial_cash: Distribution::static_value(30000.0),
+            initial_assets: Distribution::static_value(300.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ArbitrageurConfig {
+    /// Amount of arbitrageurs in the simulation, they only trade when an index
+    /// market is configured. Like market makers, they do not gossip and are
+    /// not influenced.
+    pub count: usize,
+
+    /// The smallest relative difference between the price of the index and
+    /// the value of its basket, that an arbitrageur trades on.
+    pub threshold: f32,
+
+    /// The most baskets an arbitrageur trades, creates or redeems per step,
+    /// see [`IndexConfig::creation_unit`].
+    pub order_size: Distribution,
+
+    /// Initial amount of cash an arbitrageur holds.
+    pub initial_cash: Distribution,
+
+    /// Initial amount of assets an arbitrageur holds in every market.
+    pub initial_assets: Distribution,
+}
+
+impl Default for ArbitrageurConfig {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            threshold: 0.01,
+            order_size: Distribution::static_value(5.0),
+            initial_cash: Distribution::static_value(30000.0),
+            initial_assets: Distribution::static_value(300.0),
+        }
+    }
+}
+
+/// The model with which an influenced agent updates its belief about each
+/// market, from its own belief and the beliefs of its influencers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BeliefConfig {
+    pub model: BeliefModel,
+
+    /// The weight of the own belief in the `DeGroot` model.
+    pub self_weight: f32,
+
+    /// The largest difference to the own belief, up to which an influencer is
+    /// heard in the `BoundedConfidence` model.
+    pub confidence: f32,
+
+    /// The fraction of the distance to the mean belief of the heard
+    /// influencers, that is closed in the `BoundedConfidence` model. One is
+    /// the Hegselmann-Krause model, below one with a single influencer the
+    /// Deffuant model.
+    pub convergence: f32,
+
+    /// The share of influencers in the `Majority` model, above which an agent
+    /// adopts their side. Should be at least one half.
+    pub threshold: f32,
+}
+
+impl Default for BeliefConfig {
+    fn default() -> Self {
+        Self {
+            model: BeliefModel::Rounded,
+            self_weight: 0.5,
+            confidence: 0.3,
+            convergence: 0.5,
+            threshold: 0.5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BeliefModel {
+    /// The rounded mean belief of the influencers, beliefs are always zero or
+    /// one. This is a voter model.
+    Rounded,
+    /// The weighted mean of the own belief and the mean belief of the
+    /// influencers.
+    DeGroot,
+    /// Moves towards the mean belief of the influencers that are close enough
+    /// to the own belief.
+    BoundedConfidence,
+    /// Becomes optimistic or pessimistic when more than the threshold of the
+    /// influencers are, otherwise the belief stays the same.
+    Majority,
+}
+
+/// Fundamentalists are never influenced, their belief about each market is
+/// drawn once, zero or one. If they trade, they do so like agents with the
+/// fundamentalist strategy.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FundamentalistConfig {
+    /// Let fundamentalists trade, without it they hold no cash or assets and
+    /// only influence other agents.
+    pub trading: bool,
+
+    /// Initial amount of cash a trading fundamentalist holds.
+    pub initial_cash: Distribution,
+
+    /// Initial amount of assets a trading fundamentalist holds in every
+    /// market.
+    pub initial_assets: Distribution,
+
+    /// The strategy of trading fundamentalists, it has to be the
+    /// fundamentalist strategy and its `count` is ignored.
+    pub strategy: StrategyConfig,
+}
+
+impl Default for FundamentalistConfig {
+    fn default() -> Self {
+        Self {
+            trading: false,
+            initial_cash: Distribution::static_value(3000.0),
+            initial_assets: Distribution::static_value(30.0),
+            strategy: StrategyConfig {
+                strategy: StrategyKind::Fundamentalist,
+                ..StrategyConfig::default()
+            },
+        }
+    }
+}
+
+/// A population of agents that trade with the same strategy, see
+/// [`crate::strategy`]. The parameters are drawn for every agent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StrategyConfig {
+    pub strategy: StrategyKind,
+
+    /// The amount of agents that use the strategy, taken from `agent_count`.
+    pub count: usize,
+
+    /// The amount of steps over which chartists and contrarians measure the
+    /// trend, limited by `config.market.price_history_count`.
+    pub horizon: Distribution,
+
+    /// How much a relative trend or mispricing moves the belief away from
+    /// one half.
+    pub sensitivity: Distribution,
+
+    /// The relative error of the value estimate of fundamentalists.
+    pub value_error: Distribution,
+}
+
+impl Default for StrategyConfig {
+    fn default() -> Self {
+        Self {
+            strategy: StrategyKind::NoiseTrader,
+            count: 0,
+            horizon: Distribution::static_value(10.0),
+            sensitivity: Distribution::static_value(2.0),
+            value_error: Distribution::static_value(0.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrategyKind {
+    /// Buys with the probability of its belief, which is formed by gossip.
+    NoiseTrader,
+    /// Follows the trend of the price.
+    Chartist,
+    /// Trades against the trend of the price.
+    Contrarian,
+    /// Buys below its estimate of the value and sells above it.
+    Fundamentalist,
+}
+
+impl StrategyKind {
+    pub const ALL: [StrategyKind; 4] = [
+        StrategyKind::NoiseTrader,
+        StrategyKind::Chartist,
+        StrategyKind::Contrarian,
+        StrategyKind::Fundamentalist,
+    ];
+}
+
+/// Traders leave the simulation once they are bankrupt, and new ones enter,
+/// drawn from the distributions of the [`AgentConfig`]. Agents keep their
+/// [`AgentId`](crate::agent::AgentId), new agents get new ones.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PopulationConfig {
+    /// Let agents go bankrupt and enter. Without it, agents that spend more
+    /// than they have abort the run.
+    pub enabled: bool,
+
+    /// Traders whose wealth falls below this go bankrupt. Traders that spend
+    /// more cash or sell more assets than they have always do.
+    pub bankruptcy_threshold: f32,
+
+    /// The expected amount of traders that enter every step.
+    pub entry_rate: f32,
+}
+
+impl Default for PopulationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bankruptcy_threshold: 0.0,
+            entry_rate: 0.0,
         }
     }
 }
@@ -135,7 +1023,7 @@ impl Distribution {
         Distribution::Normal { mean: val, sd: 0.0 }
     }
 
-    pub fn sample_f32(&self, rng: &mut ThreadRng) -> f32 {
+    pub fn sample_f32<R: Rng + ?Sized>(&self, rng: &mut R) -> f32 {
         match self {
             Distribution::Uniform { start, end } => Uniform::new(*start, *end).sample(rng),
             Distribution::Normal { mean, sd } => Normal::new(*mean, *sd).unwrap().sample(rng),
@@ -145,15 +1033,15 @@ impl Distribution {
         }
     }
 
-    pub fn sample_usize(&self, rng: &mut ThreadRng) -> usize {
+    pub fn sample_usize<R: Rng + ?Sized>(&self, rng: &mut R) -> usize {
         self.sample_f32(rng).round() as usize
     }
 
-    pub fn sample_isize(&self, rng: &mut ThreadRng) -> isize {
+    pub fn sample_isize<R: Rng + ?Sized>(&self, rng: &mut R) -> isize {
         self.sample_f32(rng).round() as isize
     }
 
-    pub fn sample_bool(&self, rng: &mut ThreadRng) -> bool {
+    pub fn sample_bool<R: Rng + ?Sized>(&self, rng: &mut R) -> bool {
         if let Self::Bernoulli { p } = self {
             rng.gen_bool(*p as f64)
         } else {
@@ -161,3 +1049,75 @@ impl Distribution {
         }
     }
 }
+
+/// Stores a config as toml, for formats like bincode that can not store the
+/// internally tagged distributions. Use it with `#[serde(with = ...)]`.
+pub mod as_toml {
+    use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let toml = toml::to_string(value).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&toml)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        toml::from_str(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+
+        config.news = vec![NewsConfig {
+            markets: vec![1],
+            ..NewsConfig::default()
+        }];
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.to_string(), "the news market 1 does not exist");
+        config.market.market_count = 2;
+        assert!(config.validate().is_ok());
+
+        config.market.index.market = Some(2);
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.to_string(), "the index market 2 does not exist");
+        config.market.index.market = Some(1);
+        assert!(config.validate().is_ok());
+        config.market.index.components = vec![0, 1];
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.to_string(), "the index can't contain market 1");
+        config.market.index.components.clear();
+        config.market.index.units = vec![0];
+        let err = config.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "every component of the index needs at least one asset"
+        );
+        config.market.index.units.clear();
+
+        config.market.market_count = 1;
+        config.news.clear();
+        config.market.index.market = Some(0);
+        config.market.index.components.clear();
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.to_string(), "the index needs at least one component");
+        config.market.index.market = None;
+
+        config.agent.fundamentalists.strategy.strategy = StrategyKind::Chartist;
+        let err = config.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fundamentalists can't trade with the Chartist strategy"
+        );
+    }
+}
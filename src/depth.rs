@@ -0,0 +1,205 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use plotters::{
+    prelude::{BitMapBackend, ChartBuilder, IntoDrawingArea, LineSeries, PathElement},
+    style::{Color, IntoFont, BLACK, GREEN, RED, WHITE},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::market::{Market, MarketId, Side};
+
+/// A point of a depth chart, the total quantity of all orders of one side
+/// with the same or a better limit price.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DepthLevel {
+    pub side: Side,
+    pub price: f32,
+    pub quantity: u64,
+}
+
+/// Computes the depth of one side from orders sorted by price priority, as
+/// pairs of limit price and quantity. Market orders have no price, they add to
+/// the quantity of every level.
+pub fn depth_levels(
+    side: Side,
+    orders: impl IntoIterator<Item = (Option<f32>, u32)>,
+) -> Vec<DepthLevel> {
+    let mut levels = Vec::<DepthLevel>::new();
+    let mut quantity = 0;
+    for (price, q) in orders {
+        quantity += q as u64;
+        let price = match price {
+            Some(price) => price,
+            None => continue,
+        };
+        match levels.last_mut() {
+            Some(level) if level.price == price => level.quantity = quantity,
+            _ => levels.push(DepthLevel {
+                side,
+                price,
+                quantity,
+            }),
+        }
+    }
+    levels
+}
+
+/// The depth of a market at the start of the step, before the orders of the
+/// step are executed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Depth {
+    pub market: MarketId,
+    pub step: usize,
+    /// The price of the last step.
+    pub price: f32,
+    pub levels: Vec<DepthLevel>,
+}
+
+impl Depth {
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "step,side,price,cumulative_quantity")?;
+        for level in &self.levels {
+            writeln!(
+                out,
+                "{},{:?},{},{}",
+                self.step, level.side, level.price, level.quantity
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Draw the cumulative quantity of both sides over the limit price, with a
+    /// line at the price of the last step.
+    pub fn plot(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let drawing_area = BitMapBackend::new(path.as_ref(), (1024, 512)).into_drawing_area();
+        drawing_area.fill(&WHITE)?;
+
+        let (min, max) = self
+            .levels
+            .iter()
+            .map(|l| l.price)
+            .fold((self.price, self.price), |(min, max), p| {
+                (min.min(p), max.max(p))
+            });
+        // A range of a single price can not be drawn.
+        let margin = (max - min).max(self.price * 0.01) * 0.05;
+        let y_max = self
+            .levels
+            .iter()
+            .map(|l| l.quantity)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+
+        let mut chart = ChartBuilder::on(&drawing_area)
+            .caption(
+                format!("market {} step {}", self.market, self.step),
+                ("sans-serif", 30).into_font(),
+            )
+            .margin(25)
+            .x_label_area_size(30)
+            .y_label_area_size(60)
+            .build_cartesian_2d(min - margin..max + margin, 0..y_max)?;
+        chart.configure_mesh().draw()?;
+
+        for (side, color, label) in [
+            (Side::Sell, RED, "sell depth"),
+            (Side::Buy, GREEN, "buy depth"),
+        ] {
+            let points = self
+                .levels
+                .iter()
+                .filter(|l| l.side == side)
+                .map(|l| (l.price, l.quantity));
+            chart
+                .draw_series(LineSeries::new(points, color))?
+                .label(label)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+        }
+        chart
+            .draw_series(LineSeries::new(
+                [(self.price, 0), (self.price, y_max)],
+                BLACK,
+            ))?
+            .label("price")
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], BLACK));
+
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()?;
+        drawing_area.present()?;
+        Ok(())
+    }
+}
+
+/// Decides at which steps the depth of which markets is captured, and keeps
+/// the captured depths until they are taken.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DepthCapture {
+    /// All markets if empty.
+    pub markets: Vec<MarketId>,
+    pub steps: Vec<usize>,
+    pub every: Option<usize>,
+    captured: Vec<Depth>,
+}
+
+impl DepthCapture {
+    pub fn new(markets: Vec<MarketId>, steps: Vec<usize>, every: Option<usize>) -> DepthCapture {
+        DepthCapture {
+            markets,
+            steps,
+            every,
+            captured: Vec::new(),
+        }
+    }
+
+    fn wants(&self, step: usize, market: MarketId) -> bool {
+        let at_step = self.steps.contains(&step)
+            || self.every.is_some_and(|n| n > 0 && step.is_multiple_of(n));
+        at_step && (self.markets.is_empty() || self.markets.contains(&market))
+    }
+
+    /// Capture the depth of a market if it was requested for this step.
+    pub fn capture<Mk: Market>(&mut self, step: usize, market: &Mk) {
+        if self.wants(step, market.id()) {
+            self.captured.push(Depth {
+                market: market.id(),
+                step,
+                price: market.price(),
+                levels: market.depth(),
+            });
+        }
+    }
+
+    /// The depths captured since the last call.
+    pub fn take(&mut self) -> Vec<Depth> {
+        std::mem::take(&mut self.captured)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let levels = depth_levels(
+            Side::Buy,
+            [(None, 5), (Some(1.2), 2), (Some(1.1), 1), (Some(1.1), 3)],
+        );
+        let points = levels
+            .iter()
+            .map(|l| (l.price, l.quantity))
+            .collect::<Vec<_>>();
+        assert_eq!(points, [(1.2, 7), (1.1, 11)]);
+    }
+}
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent::{Agent, AgentCollection},
+    config::FeeConfig,
+};
+
+/// The role of an agent in a trade, this decides which fees are charged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liquidity {
+    /// Trade in a call auction, where no order rests.
+    Auction,
+    /// The order rested in the book.
+    Maker,
+    /// The order was matched when it arrived.
+    Taker,
+}
+
+/// The amount of cash collected by a market during one step.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Revenue {
+    pub fees: f32,
+    pub taxes: f32,
+}
+
+impl Revenue {
+    pub fn total(&self) -> f32 {
+        self.fees + self.taxes
+    }
+}
+
+/// Charges the fees and taxes of a market and keeps track of the revenue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fees {
+    fixed: f32,
+    proportional: f32,
+    maker: f32,
+    taker: f32,
+    tax: f32,
+    redistribute: bool,
+    /// Revenue of the current step.
+    collected: Revenue,
+    /// Revenue of the last finished step.
+    revenue: Revenue,
+}
+
+impl Fees {
+    pub fn new(config: &FeeConfig) -> Fees {
+        Fees {
+            fixed: config.fixed,
+            proportional: config.proportional,
+            maker: config.maker,
+            taker: config.taker,
+            tax: config.tax,
+            redistribute: config.redistribute,
+            collected: Revenue::default(),
+            revenue: Revenue::default(),
+        }
+    }
+
+    /// Whether anything is charged at all.
+    pub fn is_enabled(&self) -> bool {
+        [
+            self.fixed,
+            self.proportional,
+            self.maker,
+            self.taker,
+            self.tax,
+        ]
+        .iter()
+        .any(|&f| f != 0.0)
+    }
+
+    fn fee_rate(&self, liquidity: Liquidity) -> f32 {
+        self.proportional
+            + match liquidity {
+                Liquidity::Auction => 0.0,
+                Liquidity::Maker => self.maker,
+                Liquidity::Taker => self.taker,
+            }
+    }
+
+    /// The price of one asset including the proportional fees and taxes.
+    pub fn gross_price(&self, price: f32, liquidity: Liquidity) -> f32 {
+        price * (1.0 + self.fee_rate(liquidity) + self.tax)
+    }
+
+    /// The highest gross price of an order in a book, regardless of whether
+    /// it is filled as maker or taker. Resting buy orders reserve this.
+    pub fn book_gross_price(&self, price: f32) -> f32 {
+        if self.maker > self.taker {
+            self.gross_price(price, Liquidity::Maker)
+        } else {
+            self.gross_price(price, Liquidity::Taker)
+        }
+    }
+
+    /// The amount of assets that can be bought with the cash, when the price
+    /// already includes all proportional charges.
+    pub fn affordable(&self, cash: f32, gross_price: f32) -> u32 {
+        ((cash - self.fixed).max(0.0) / gross_price) as u32
+    }
+
+    /// Charge the fees and taxes of a trade to one side of it. An agent pays
+    /// at most the cash that is not bound by resting orders.
+    pub fn charge<const M: usize>(
+        &mut self,
+        agent: &mut Agent<M>,
+        value: f32,
+        liquidity: Liquidity,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let available = agent.available_cash();
+        let fee = (self.fixed + value * self.fee_rate(liquidity)).min(available);
+        let tax = (value * self.tax).min(available - fee);
+
+        agent.cash -= fee + tax;
+        self.collected.fees += fee;
+        self.collected.taxes += tax;
+    }
+
+    /// Finish the current step. Its revenue is paid out to the agents if the
+    /// market is configured to do so.
+    pub fn end_step<const M: usize>(&mut self, agents: &mut AgentCollection<M>) {
+        self.revenue = std::mem::take(&mut self.collected);
+        if self.redistribute {
+            agents.distribute_cash(self.revenue.total());
+        }
+    }
+
+    /// The revenue collected during the last step.
+    pub fn revenue(&self) -> Revenue {
+        self.revenue
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{config::Config, simulation::SimRng};
+
+    #[test]
+    fn test_charge_and_redistribute() {
+        let mut config = Config::default();
+        config.agent.agent_count = 2;
+        config.agent.fundamentalist_count = 0;
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let cash = agents.total_cash();
+
+        let mut fees = Fees::new(&FeeConfig {
+            fixed: 1.0,
+            proportional: 0.01,
+            taker: 0.02,
+            tax: 0.005,
+            redistribute: true,
+            ..FeeConfig::default()
+        });
+        assert_eq!(fees.gross_price(100.0, Liquidity::Maker), 101.5);
+        assert_eq!(fees.affordable(203.0, 101.5), 1);
+
+        fees.charge(agents.agent_mut(0), 1000.0, Liquidity::Taker);
+        fees.charge(agents.agent_mut(1), 1000.0, Liquidity::Auction);
+        assert_eq!(agents.total_cash(), cash - 31.0 - 11.0 - 10.0);
+
+        fees.end_step(&mut agents);
+        assert_eq!(
+            fees.revenue(),
+            Revenue {
+                fees: 42.0,
+                taxes: 10.0
+            }
+        );
+        assert_eq!(agents.total_cash(), cash);
+    }
+}
@@ -0,0 +1,122 @@
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, FundamentalConfig, FundamentalJump},
+    market::MarketId,
+    simulation::SimRng,
+};
+
+/// The intrinsic value of the asset of a market, see [`FundamentalConfig`]
+/// for the process it follows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fundamental {
+    value: f32,
+    log_mean: f32,
+    drift: f32,
+    volatility: f32,
+    mean_reversion: f32,
+    /// The jumps of this market.
+    jumps: Vec<FundamentalJump>,
+    step: usize,
+    rng: SimRng,
+}
+
+impl Fundamental {
+    pub fn new(
+        config: &FundamentalConfig,
+        market: MarketId,
+        initial_price: f32,
+        rng: SimRng,
+    ) -> Fundamental {
+        let value = config.initial_value.unwrap_or(initial_price);
+        Fundamental {
+            value,
+            log_mean: config.mean.unwrap_or(value).ln(),
+            drift: config.drift,
+            volatility: config.volatility,
+            mean_reversion: config.mean_reversion,
+            jumps: config
+                .jumps
+                .iter()
+                .filter(|j| j.market.is_none_or(|m| m == market))
+                .cloned()
+                .collect(),
+            step: 0,
+            rng,
+        }
+    }
+
+    /// The fundamental value of a market, if enabled in the config. The
+    /// random number generator is only used if it is.
+    pub fn from_config(config: &Config, market: MarketId, rng: &mut SimRng) -> Option<Fundamental> {
+        config.market.fundamental.enabled.then(|| {
+            Fundamental::new(
+                &config.market.fundamental,
+                market,
+                config.market.initial_price,
+                SimRng::seed_from_u64(rng.gen()),
+            )
+        })
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// Advance the process by one step, this is called once per step by the
+    /// market.
+    pub fn step(&mut self) {
+        let shock: f32 = self.rng.sample(StandardNormal);
+        let log_value = self.value.ln();
+        let change = self.drift
+            + self.mean_reversion * (self.log_mean - log_value)
+            + self.volatility * shock;
+        self.value = (log_value + change).exp();
+
+        let step = self.step;
+        for jump in self.jumps.iter().filter(|j| j.step == step) {
+            self.value *= 1.0 + jump.size;
+        }
+        self.step += 1;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_mean_reversion_and_jumps() {
+        let config = FundamentalConfig {
+            enabled: true,
+            initial_value: Some(200.0),
+            mean: Some(100.0),
+            mean_reversion: 0.5,
+            jumps: vec![
+                FundamentalJump {
+                    step: 20,
+                    market: None,
+                    size: -0.5,
+                },
+                FundamentalJump {
+                    step: 20,
+                    market: Some(1),
+                    size: 1.0,
+                },
+            ],
+            ..FundamentalConfig::default()
+        };
+        let mut fundamental = Fundamental::new(&config, 0, 1.0, SimRng::seed_from_u64(0));
+        assert_eq!(fundamental.value(), 200.0);
+
+        for _ in 0..20 {
+            fundamental.step();
+        }
+        assert!((fundamental.value() - 100.0).abs() < 0.01);
+
+        fundamental.step();
+        assert!((fundamental.value() - 50.0).abs() < 0.01);
+    }
+}
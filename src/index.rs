@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    market::{Market, MarketId},
+};
+
+/// A market whose shares are baskets of assets of other markets, see
+/// [`crate::config::IndexConfig`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Index {
+    market: MarketId,
+    /// The component markets with the amount of their assets in one basket.
+    basket: Vec<(MarketId, u32)>,
+    /// The amount of index shares created from one basket.
+    creation_unit: u32,
+}
+
+impl Index {
+    /// The index of the config, if it has one. The markets of the index are
+    /// checked by [`Config::validate`].
+    pub fn from_config(config: &Config) -> Option<Index> {
+        let index = &config.market.index;
+        let market = index.market?;
+        let market_count = config.market.market_count;
+
+        let components = if index.components.is_empty() {
+            (0..market_count).filter(|&m| m != market).collect()
+        } else {
+            index.components.clone()
+        };
+        let basket = components
+            .into_iter()
+            .enumerate()
+            .map(|(i, m)| (m, index.units.get(i).copied().unwrap_or(1)))
+            .collect::<Vec<_>>();
+        let creation_unit = index
+            .creation_unit
+            .unwrap_or_else(|| basket.iter().map(|(_, units)| units).sum())
+            .max(1);
+        Some(Index {
+            market,
+            basket,
+            creation_unit,
+        })
+    }
+
+    pub fn market(&self) -> MarketId {
+        self.market
+    }
+
+    pub fn basket(&self) -> &[(MarketId, u32)] {
+        &self.basket
+    }
+
+    pub fn creation_unit(&self) -> u32 {
+        self.creation_unit
+    }
+
+    /// The net asset value of an index share, its part of the value of a
+    /// basket at the given prices.
+    pub fn nav_at(&self, price: impl Fn(MarketId) -> f32) -> f32 {
+        let basket = self
+            .basket
+            .iter()
+            .map(|&(m, units)| units as f32 * price(m))
+            .sum::<f32>();
+        basket / self.creation_unit as f32
+    }
+
+    /// The net asset value of an index share at current prices.
+    pub fn nav<Mk: Market>(&self, markets: &[Mk]) -> f32 {
+        self.nav_at(|m| markets[m].price())
+    }
+
+    /// The relative difference between the price of the index and its net
+    /// asset value, positive if the index trades at a premium.
+    pub fn premium<Mk: Market>(&self, markets: &[Mk]) -> f32 {
+        markets[self.market].price() / self.nav(markets) - 1.0
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_basket() {
+        let mut config = Config::default();
+        config.market.market_count = 4;
+        assert!(Index::from_config(&config).is_none());
+
+        config.market.index.market = Some(1);
+        let index = Index::from_config(&config).unwrap();
+        assert_eq!(index.basket(), [(0, 1), (2, 1), (3, 1)]);
+        assert_eq!(index.nav_at(|_| 100.0), 100.0);
+
+        config.market.index.components = vec![3, 0];
+        config.market.index.units = vec![2];
+        let index = Index::from_config(&config).unwrap();
+        assert_eq!(index.basket(), [(3, 2), (0, 1)]);
+        assert_eq!(index.nav_at(|m| m as f32 + 1.0), 3.0);
+
+        config.market.index.creation_unit = Some(1);
+        let index = Index::from_config(&config).unwrap();
+        assert_eq!(index.nav_at(|m| m as f32 + 1.0), 9.0);
+    }
+}
@@ -1,26 +1,48 @@
-use std::{error::Error, path::PathBuf, thread};
+use std::{collections::HashMap, error::Error, fs::File, io::BufReader, path::PathBuf, thread};
 
 use clap::{AppSettings, Clap};
 use config::Config;
+use depth::DepthCapture;
+use market::MarketId;
 use plotters::{
     prelude::{BitMapBackend, IntoDrawingArea},
     style::WHITE,
 };
+use rand::Rng;
 // use plotters::{
 //     prelude::{BitMapBackend, IntoDrawingArea},
 //     style::WHITE,
 // };
 use report::Reporter;
 use simulation::Simulation;
-use speedy2d::{Window, window::{UserEventSender, WindowCreationOptions, WindowSize}};
+use snapshot::Snapshot;
+use speedy2d::{
+    window::{UserEventSender, WindowCreationOptions, WindowSize},
+    Window,
+};
+use tape::TradeTape;
 use toml::to_string_pretty;
 use window::Data;
 
 pub mod agent;
+pub mod audit;
+mod belief;
+pub mod book;
+pub mod breaker;
 pub mod config;
+pub mod depth;
+pub mod fee;
+pub mod fundamental;
+mod index;
+pub mod margin;
 pub mod market;
+mod news;
 pub mod report;
 pub mod simulation;
+pub mod snapshot;
+pub mod strategy;
+pub mod tape;
+mod volatility;
 pub mod window;
 
 /// Application to investigate market behavior in gossiping agents.
@@ -36,6 +58,7 @@ struct Opts {
 enum SubCommand {
     Run(RunCommand),
     WriteConfig(WriteConfigCommand),
+    Replay(ReplayCommand),
 }
 
 /// Run a simulation.
@@ -59,13 +82,95 @@ struct RunCommand {
     #[clap(short, long)]
     plot: bool,
 
-    /// Write a csv with the reported values.
+    /// Write a csv with the reported values. The seed and the limit price
+    /// model are written above the header, in lines starting with '#'.
     #[clap(long)]
     csv_write: bool,
 
+    /// Leave the first N steps out of the reported values and events. The
+    /// simulation itself is not changed, and the trade tape and depth
+    /// captures still cover every step, so the tape can be replayed.
+    #[clap(long, default_value = "0")]
+    burn_in: usize,
+
+    /// Only report the values of every K-th step after the burn in, events
+    /// are still reported in every step. When resuming, the sampling of the
+    /// snapshot is used.
+    #[clap(long, default_value = "1")]
+    sample_every: usize,
+
     /// Open a window and show a visualization while the simulation is running.
     #[clap(short, long)]
     window: bool,
+
+    /// Seed for the random number generator, overrides the seed from the
+    /// configuration. Repetitions use consecutive seeds starting at this one.
+    #[clap(short, long)]
+    seed: Option<u64>,
+
+    /// Continue the run stored in a snapshot. The configuration and seed are
+    /// taken from the snapshot, the run continues until it reaches the run
+    /// length.
+    #[clap(long)]
+    resume: Option<PathBuf>,
+
+    /// Write a snapshot of the running simulation every N steps, it can be
+    /// continued later using '--resume'. With several repetitions the index
+    /// of the repetition is appended to the file name.
+    #[clap(long)]
+    snapshot_every: Option<usize>,
+
+    /// Record every order and execution to a trade tape, it can be checked
+    /// using 'rug-mas replay'. With several repetitions the index of the
+    /// repetition is appended to the file name. When resuming, the tape of
+    /// the resumed run is continued.
+    #[clap(long)]
+    tape: bool,
+
+    /// Check after every step that trades conserve cash and assets, that no
+    /// balance is negative and that prices and volatilities are finite.
+    /// Violations are written to a report instead of aborting the run, one
+    /// per repetition.
+    #[clap(long)]
+    audit: bool,
+
+    /// Export the order book depth at these steps, as csv and as a depth
+    /// chart, into a directory next to the other output files. With several
+    /// repetitions every one gets its own directory.
+    #[clap(long, use_delimiter = true)]
+    depth_steps: Vec<usize>,
+
+    /// Export the order book depth every N steps.
+    #[clap(long)]
+    depth_every: Option<usize>,
+
+    /// The markets whose depth is exported, all markets if not given.
+    #[clap(long, use_delimiter = true)]
+    depth_markets: Vec<MarketId>,
+}
+
+impl RunCommand {
+    /// The name of the output files, without extension.
+    fn output_name(&self) -> String {
+        self.config
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.split('.').next())
+            .unwrap_or("result")
+            .to_owned()
+    }
+
+    /// The name of the files written per repetition, like the snapshot and the
+    /// trade tape, without extension. With several repetitions every one gets
+    /// its own files.
+    fn run_name(&self, run_index: u32) -> String {
+        if self.repetitions > 1 {
+            format!("{}_{}", self.output_name(), run_index)
+        } else {
+            self.output_name()
+        }
+    }
 }
 
 /// Export the default configuration.
@@ -76,12 +181,56 @@ struct WriteConfigCommand {
     config: PathBuf,
 }
 
+/// Replay a trade tape and check that it reproduces the recorded prices.
+#[derive(Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct ReplayCommand {
+    /// Path to the trade tape, written using 'rug-mas run --tape'.
+    tape: PathBuf,
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Opts::parse();
 
     match args.subcmd {
         SubCommand::Run(rc) => run_simulation(rc),
         SubCommand::WriteConfig(wc) => write_config(wc),
+        SubCommand::Replay(rc) => replay_tape(rc),
+    }
+}
+
+fn replay_tape(cmd: ReplayCommand) -> Result<(), Box<dyn Error>> {
+    let file = BufReader::new(File::open(cmd.tape)?);
+    let result = tape::replay(file)?;
+
+    println!(
+        "Replayed {} clearings with {} orders and {} executions",
+        result.clearings, result.orders, result.executions
+    );
+    if result.book_steps > 0 {
+        println!("Skipped {} steps of order book markets", result.book_steps);
+    }
+    if result.halted_steps > 0 {
+        println!("Skipped {} halted steps", result.halted_steps);
+    }
+    for m in &result.mismatches {
+        println!(
+            "Step {} market {}: recorded price {} for {} assets, replayed price {} for {} assets",
+            m.step, m.market, m.recorded.0, m.recorded.1, m.replayed.0, m.replayed.1
+        );
+    }
+    for (step, market, side) in &result.unbalanced {
+        println!(
+            "Step {} market {}: {:?} executions do not match the cleared quantity",
+            step, market, side
+        );
+    }
+
+    if result.mismatches.is_empty() && result.unbalanced.is_empty() {
+        println!("All recorded prices were reproduced");
+        Ok(())
+    } else {
+        Err("trade tape does not reproduce the recorded prices".into())
     }
 }
 
@@ -92,11 +241,15 @@ fn write_config(cmd: WriteConfigCommand) -> Result<(), Box<dyn Error>> {
 }
 
 fn run_simulation(cmd: RunCommand) -> Result<(), Box<dyn Error>> {
-    let config = cmd
-        .config
-        .as_ref()
-        .map(Config::load)
-        .unwrap_or_else(|| Ok(Config::default()))?;
+    let snapshot = cmd.resume.as_ref().map(Snapshot::load).transpose()?;
+    let config = match &snapshot {
+        Some(snapshot) => snapshot.config()?,
+        None => cmd
+            .config
+            .as_ref()
+            .map(Config::load)
+            .unwrap_or_else(|| Ok(Config::default()))?,
+    };
 
     if cmd.window {
         let size = WindowSize::MarginPhysicalPixels(100);
@@ -104,35 +257,112 @@ fn run_simulation(cmd: RunCommand) -> Result<(), Box<dyn Error>> {
         let window = Window::<Data>::new_with_user_events("Title", opts).unwrap();
         let event_sender = window.create_user_event_sender();
 
-        thread::spawn(move || sim_loop(cmd, config, Some((1, event_sender))));
+        thread::spawn(move || sim_loop(cmd, config, snapshot, Some((1, event_sender))));
 
         window.run_loop(window::MyWindowHandler::default());
     } else {
-        sim_loop(cmd, config, None);
+        sim_loop(cmd, config, snapshot, None);
     };
 
     Ok(())
 }
 
-fn sim_loop(cmd: RunCommand, config: Config, event_sender: Option<(usize, UserEventSender<Data>)>) {
-    for _run_index in 0..cmd.repetitions {
-        let mut reporter = Reporter::new();
-        let mut sim = Simulation::new(&config);
-        for step in 0..cmd.run_length {
+fn sim_loop(
+    cmd: RunCommand,
+    config: Config,
+    mut snapshot: Option<Snapshot>,
+    event_sender: Option<(usize, UserEventSender<Data>)>,
+) {
+    let base_seed = snapshot
+        .as_ref()
+        .map(|s| s.seed)
+        .or(cmd.seed)
+        .or(config.seed)
+        .unwrap_or_else(|| rand::thread_rng().gen());
+
+    for run_index in 0..cmd.repetitions {
+        let seed = base_seed.wrapping_add(run_index as u64);
+
+        let resumed = snapshot.is_some();
+        let (first_step, mut sim, mut reporter) = match snapshot.take() {
+            Some(s) => {
+                println!("Resuming simulation with seed {} at step {}", seed, s.step);
+                (s.step, s.sim, s.reporter)
+            }
+            None => {
+                println!("Running simulation with seed {}", seed);
+                let mut reporter = Reporter::new();
+                reporter.set_metadata("seed", seed);
+                reporter.set_metadata("limit_model", &config.market.limit_price);
+                reporter.set_sampling(cmd.burn_in, cmd.sample_every);
+                (0, Simulation::new(&config, seed), reporter)
+            }
+        };
+        if cmd.audit {
+            sim.enable_audit();
+        }
+        let depth_dir = format!("{}_depth", cmd.run_name(run_index));
+        if !cmd.depth_steps.is_empty() || cmd.depth_every.is_some() {
+            std::fs::create_dir_all(&depth_dir).expect("can't create depth directory");
+            sim.capture_depth(DepthCapture::new(
+                cmd.depth_markets.clone(),
+                cmd.depth_steps.clone(),
+                cmd.depth_every,
+            ));
+        }
+        // The number of depth frames written per market.
+        let mut frames = HashMap::<MarketId, usize>::new();
+
+        let mut tape = cmd.tape.then(|| {
+            let path = format!("{}.tape", cmd.run_name(run_index));
+            if resumed {
+                TradeTape::append(path, &config).expect("can't open trade tape")
+            } else {
+                TradeTape::create(path, &config).expect("can't create trade tape")
+            }
+        });
+
+        for step in first_step..cmd.run_length {
             reporter.set_step(step);
-            sim.step(step, &mut reporter);
-            
+            if let Some(tape) = &mut tape {
+                tape.set_step(step);
+            }
+            sim.step(step, &mut reporter, tape.as_mut());
+
+            for depth in sim.take_depths() {
+                let frame = frames.entry(depth.market).or_default();
+                let path = format!("{}/depth_{}_{:05}", depth_dir, depth.market, frame);
+                depth
+                    .write_csv(format!("{}.csv", path))
+                    .expect("can't write depth");
+                depth
+                    .plot(format!("{}.png", path))
+                    .expect("can't plot depth");
+                *frame += 1;
+            }
+
+            if let Some(every) = cmd.snapshot_every {
+                if (step + 1).is_multiple_of(every) {
+                    let path = format!("{}.snapshot", cmd.run_name(run_index));
+                    Snapshot::new(&config, seed, step + 1, sim.clone(), reporter.clone())
+                        .and_then(|s| s.save(path))
+                        .expect("can't write snapshot");
+                }
+            }
+
             if let Some((update_rate, es)) = &event_sender {
                 std::thread::sleep(std::time::Duration::from_secs_f32(0.07));
                 if step % update_rate == 0 {
                     let data = Data {
                         sim: sim.clone(),
-                        report: Reporter::new(),// reporter.clone(),
+                        report: Reporter::new(), // reporter.clone(),
                     };
                     es.send_event(data).unwrap();
                 }
             }
-
+        }
+        if let Some(tape) = &mut tape {
+            tape.flush();
         }
         if cmd.plot {
             let drawing_area = BitMapBackend::new("plot.png", (10240, 5120)).into_drawing_area();
@@ -140,16 +370,17 @@ fn sim_loop(cmd: RunCommand, config: Config, event_sender: Option<(usize, UserEv
             reporter.render_chart(drawing_area);
         }
         if cmd.csv_write {
-            let mut name = cmd
-                .config
-                .as_ref()
-                .and_then(|p| p.file_name())
-                .and_then(|n| n.to_str())
-                .and_then(|n| n.split('.').next())
-                .unwrap_or("result")
-                .to_owned();
-            name += ".csv";
-            reporter.write_csv(name)
+            reporter.write_csv(format!("{}.csv", cmd.output_name()));
+            reporter.write_events(format!("{}_events.txt", cmd.output_name()));
+        }
+        if let Some(audit) = sim.audit() {
+            let path = format!("{}_audit.txt", cmd.run_name(run_index));
+            audit.write(&path).expect("can't write audit report");
+            println!(
+                "Audit found {} violations, see {}",
+                audit.violations().len(),
+                path
+            );
         }
     }
 }
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::MarginConfig;
+
+/// Keeps track of the assets an agent borrowed, and how much more it may
+/// borrow. Borrowed cash is simply a negative cash balance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarginAccount {
+    /// Assets per market that were borrowed and sold.
+    pub short: Vec<u32>,
+    allow_short: bool,
+    /// The value of the positions the agent may still add during this step.
+    buying_power: f32,
+    /// Whether the positions of the agent are being closed.
+    liquidating: bool,
+}
+
+impl MarginAccount {
+    pub fn new(config: &MarginConfig, market_count: usize) -> MarginAccount {
+        MarginAccount {
+            short: vec![0; market_count],
+            allow_short: config.allow_short,
+            buying_power: 0.0,
+            liquidating: false,
+        }
+    }
+
+    pub fn allows_short(&self) -> bool {
+        self.allow_short
+    }
+
+    pub fn is_liquidating(&self) -> bool {
+        self.liquidating
+    }
+
+    pub fn buying_power(&self) -> f32 {
+        self.buying_power
+    }
+
+    /// Reduce the buying power by the value of a new order.
+    pub fn use_buying_power(&mut self, value: f32) {
+        self.buying_power = (self.buying_power - value).max(0.0);
+    }
+
+    /// Checks the margin requirement with the equity and the value of all
+    /// positions at current prices, this is done at the start of every step.
+    /// Returns true if a liquidation was started.
+    pub fn update(&mut self, config: &MarginConfig, equity: f32, exposure: f32) -> bool {
+        let liquidating = exposure > 0.0 && equity < config.maintenance_margin * exposure;
+        let started = liquidating && !self.liquidating;
+
+        self.liquidating = liquidating;
+        self.buying_power = if liquidating {
+            0.0
+        } else {
+            (config.max_leverage * equity - exposure).max(0.0)
+        };
+        started
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_requirements() {
+        let config = MarginConfig {
+            enabled: true,
+            max_leverage: 2.0,
+            maintenance_margin: 0.25,
+            ..MarginConfig::default()
+        };
+        let mut account = MarginAccount::new(&config, 1);
+
+        assert!(!account.update(&config, 1000.0, 500.0));
+        assert_eq!(account.buying_power(), 1500.0);
+        account.use_buying_power(2000.0);
+        assert_eq!(account.buying_power(), 0.0);
+
+        assert!(account.update(&config, 100.0, 500.0));
+        assert!(account.is_liquidating());
+        assert!(!account.update(&config, 100.0, 500.0));
+        assert!(!account.update(&config, 100.0, 0.0));
+        assert!(!account.is_liquidating());
+    }
+}
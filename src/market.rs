@@ -1,101 +1,335 @@
-use std::{array::IntoIter, cmp::Ordering, collections::VecDeque, ops::Div, path::Path};
+use std::{cmp::Ordering, collections::VecDeque, ops::Div};
 
-use plotters::{
-    prelude::{BitMapBackend, ChartBuilder, IntoDrawingArea, LineSeries, PathElement},
-    style::{Color, IntoFont, BLACK, GREEN, RED, WHITE},
-};
-use rand::prelude::{thread_rng, Distribution};
+use rand::prelude::{Distribution, SliceRandom};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     agent::{AgentCollection, AgentId},
-    config::Config,
+    book::OrderBookMarket,
+    breaker::CircuitBreaker,
+    config::{Allocation, Config, GridConfig, LimitPriceConfig, LimitPriceModel, MarketMechanism},
+    depth::{depth_levels, DepthLevel},
+    fee::{Fees, Liquidity, Revenue},
+    fundamental::Fundamental,
+    simulation::SimRng,
+    tape::TradeTape,
+    volatility::VolatilityEstimator,
 };
 
 pub type MarketId = usize;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// How long an order takes part in trading, and whether it has a limit price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderType {
+    /// A limit order with the usual lifetime of the market, a single clearing
+    /// in a call auction and `order_lifetime` steps in an order book.
+    Limit,
+    /// An order without a limit price, it is executed at any price. The
+    /// unfilled remainder is discarded.
+    Market,
+    /// A limit order whose unfilled remainder is discarded right away.
+    ImmediateOrCancel,
+    /// A limit order that takes part in trading for the given number of
+    /// steps, including the one it was placed in.
+    GoodTill(usize),
+}
+
+/// A market mechanism. Agents submit orders during a step, which are executed
+/// once [`Market::step`] is called.
+pub trait Market {
+    fn new(config: &Config, id: MarketId, rng: SimRng) -> Self
+    where
+        Self: Sized;
+
+    fn id(&self) -> MarketId;
+
+    /// The most recent prices, starting with the current one.
+    fn price_history(&self) -> &VecDeque<f32>;
+
+    fn price(&self) -> f32 {
+        self.price_history()[0]
+    }
+
+    fn price_ago(&self, delay: usize) -> f32 {
+        self.price_history()[delay]
+    }
+
+    fn volatility(&self) -> f32;
+
+    /// The difference between the best ask and the best bid, only available
+    /// for markets that keep orders across steps.
+    fn spread(&self) -> Option<f32> {
+        None
+    }
+
+    /// Submit a buy order that spends at most the given amount of cash, the
+    /// limit price is chosen by the market unless it is a market order. The
+    /// belief is the state of the agent for this market, some limit price
+    /// models depend on it.
+    fn buy_order(&mut self, agent: AgentId, cash_quantity: f32, belief: f32, order_type: OrderType);
+
+    /// Submit a sell order, the limit price is chosen by the market unless it
+    /// is a market order.
+    fn sell_order(
+        &mut self,
+        agent: AgentId,
+        asset_quantity: u32,
+        belief: f32,
+        order_type: OrderType,
+    );
+
+    /// Submit an order with a given limit price.
+    fn limit_order(&mut self, agent: AgentId, side: Side, asset_quantity: u32, limit_price: f32);
+
+    /// Cancel all orders of an agent that rest in the market. Markets that do
+    /// not keep orders across steps have nothing to cancel.
+    fn cancel_orders(&mut self, _agent: AgentId) {}
+
+    /// The fees and taxes collected during the last step, only available for
+    /// markets that charge anything.
+    fn revenue(&self) -> Option<Revenue> {
+        None
+    }
+
+    /// The intrinsic value of the asset, if the market has one.
+    fn fundamental(&self) -> Option<f32> {
+        None
+    }
+
+    /// The cumulative quantity of the orders in the market per limit price,
+    /// buy orders from the highest price down and sell orders from the lowest
+    /// price up.
+    fn depth(&self) -> Vec<DepthLevel>;
+
+    /// The circuit breaker of the market, if it has any rules.
+    fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        None
+    }
+
+    /// Move the current price by a relative amount, as if the last step had
+    /// cleared there. Used for news that reprices the asset at once.
+    fn jump_price(&mut self, size: f32);
+
+    /// Call this after all orders have been submitted, this executes the
+    /// orders and computes a new price and volatility.
+    fn step<const M: usize>(
+        &mut self,
+        agents: &mut AgentCollection<M>,
+        tape: Option<&mut TradeTape>,
+    );
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GenoaMarket {
     id: MarketId,
     price_history: VecDeque<f32>,
     price_history_count: usize,
     volatility: f32,
+    estimator: VolatilityEstimator,
     buy_orders: Vec<GenoaOrder>,
     sell_orders: Vec<GenoaOrder>,
+    /// Agents whose carried over orders are removed before the next clearing.
+    cancellations: Vec<AgentId>,
+    limit_price: LimitPriceConfig,
+    allocation: Allocation,
+    grid: GridConfig,
+    fees: Fees,
+    breaker: CircuitBreaker,
+    fundamental: Option<Fundamental>,
+    rng: SimRng,
 }
 
 impl GenoaMarket {
-    pub fn new(config: &Config, id: MarketId) -> GenoaMarket {
-        GenoaMarket {
-            id,
-            price_history: IntoIter::new([config.market.initial_price; 3]).collect(),
-            price_history_count: config.market.price_history_count,
-            volatility: config.market.initial_volatility,
-            buy_orders: Vec::new(),
-            sell_orders: Vec::new(),
-        }
-    }
-
-    /// Call this after all orders have been submitted, this will execute orders, as well as
-    /// computing a new price and volatility.
-    pub fn step<const M: usize>(&mut self, agents: &mut AgentCollection<M>) {
+    /// Sorts the submitted orders and computes the clearing price and the
+    /// amount of assets that would be executed, without executing anything.
+    pub fn clearing(&mut self) -> Option<(f32, u32)> {
         self.sort_orders();
+        self.compute_price()
+    }
 
-        let (price, amount_executed) = self.compute_price().unwrap_or_else(|| (self.price(), 0));
+    /// Discard all submitted orders.
+    pub fn clear_orders(&mut self) {
+        self.buy_orders.clear();
+        self.sell_orders.clear();
+    }
 
-        self.record_price(price);
+    /// Submit an order for the next clearing only, `None` as limit price
+    /// makes it a market order.
+    pub fn submit(
+        &mut self,
+        agent: AgentId,
+        side: Side,
+        asset_quantity: u32,
+        limit_price: Option<f32>,
+    ) {
+        let order = GenoaOrder::new(agent, asset_quantity, limit_price, OrderType::Limit);
+        self.push_order(side, order);
+    }
 
-        self.execute_buy_orders(amount_executed, agents, price);
-        self.execute_sell_orders(amount_executed, agents, price);
+    /// Add an order, rounded to the grid of the market.
+    fn push_order(&mut self, side: Side, mut order: GenoaOrder) {
+        order.asset_quantity = self.grid.lots(order.asset_quantity);
+        order.limit_price = order.limit_price.map(|p| self.grid.limit_price(side, p));
+        match side {
+            Side::Buy => self.buy_orders.push(order),
+            Side::Sell => self.sell_orders.push(order),
+        }
+    }
 
-        self.compute_volatility();
+    /// Sample a limit price around the current price, see [`sample_limit_price`].
+    fn sample_limit(&mut self, side: Side, belief: f32) -> f32 {
+        sample_limit_price(
+            &self.limit_price,
+            side,
+            self.price(),
+            self.volatility,
+            belief,
+            &mut self.rng,
+        )
+    }
 
-        self.buy_orders.clear();
-        self.sell_orders.clear();
+    /// Market buy orders are sized with the price of the last step, at the
+    /// clearing price they are reduced to what the agents can pay. Returns
+    /// true if any order was reduced. Agents with a margin account borrow the
+    /// missing cash instead.
+    fn limit_market_buys<const M: usize>(
+        &mut self,
+        agents: &AgentCollection<M>,
+        price: f32,
+    ) -> bool {
+        let gross_price = self.fees.gross_price(price, Liquidity::Auction);
+        let mut reduced = false;
+        for bo in self
+            .buy_orders
+            .iter_mut()
+            .filter(|o| o.limit_price.is_none())
+        {
+            let agent = agents.agent(bo.agent);
+            if agent.margin.is_some() {
+                continue;
+            }
+            let affordable = self
+                .grid
+                .lots(self.fees.affordable(agent.available_cash(), gross_price));
+            if bo.asset_quantity > affordable {
+                bo.asset_quantity = affordable;
+                reduced = true;
+            }
+        }
+        reduced
     }
 
-    fn compute_volatility(&mut self) {
-        if self.price_history.len() < 3 {
-            // We need at least three values to compute the log returns
+    /// Remove the orders carried over from earlier steps of agents that
+    /// cancelled them, and release what was reserved for them. Orders placed
+    /// during this step are kept.
+    fn remove_cancelled<const M: usize>(&mut self, agents: &mut AgentCollection<M>) {
+        let cancellations = std::mem::take(&mut self.cancellations);
+        if cancellations.is_empty() {
             return;
         }
+        let (id, fees) = (self.id, &self.fees);
+        for (side, orders) in [
+            (Side::Buy, &mut self.buy_orders),
+            (Side::Sell, &mut self.sell_orders),
+        ] {
+            orders.retain(|o| {
+                let cancel = o.carried && cancellations.contains(&o.agent);
+                if cancel {
+                    let price = reserved_price(fees, side, o.limit_price);
+                    agents
+                        .agent_mut(o.agent)
+                        .release(id, side, o.asset_quantity, price);
+                }
+                !cancel
+            });
+        }
+    }
 
-        let num_log_returns = (self.price_history.len() - 1) as f32;
-
-        let log_returns = self
-            .price_history
-            .iter()
-            .zip(self.price_history.iter().skip(1))
-            .map(|(n, np1)| (np1 / n).ln());
+    /// Release the cash and assets reserved for orders carried over from
+    /// earlier steps, from now on they are treated like new orders.
+    fn release_carried<const M: usize>(&mut self, agents: &mut AgentCollection<M>) {
+        for (side, orders) in [
+            (Side::Buy, &mut self.buy_orders),
+            (Side::Sell, &mut self.sell_orders),
+        ] {
+            for o in orders.iter_mut().filter(|o| o.carried) {
+                let price = reserved_price(&self.fees, side, o.limit_price);
+                agents
+                    .agent_mut(o.agent)
+                    .release(self.id, side, o.asset_quantity, price);
+                o.carried = false;
+            }
+        }
+    }
 
-        let log_return_average = log_returns.clone().sum::<f32>() / num_log_returns;
+    /// Keep the unfilled remainder of good-till orders for the next steps and
+    /// reserve the cash or assets needed to fill it. All other orders are
+    /// discarded.
+    fn carry_over<const M: usize>(&mut self, agents: &mut AgentCollection<M>) {
+        for (side, orders) in [
+            (Side::Buy, &mut self.buy_orders),
+            (Side::Sell, &mut self.sell_orders),
+        ] {
+            orders.retain(|o| o.clearings_left > 0 && o.asset_quantity > 0);
+            for o in orders.iter_mut() {
+                let price = reserved_price(&self.fees, side, o.limit_price);
+                agents
+                    .agent_mut(o.agent)
+                    .reserve(self.id, side, o.asset_quantity, price);
+                o.clearings_left -= 1;
+                o.carried = true;
+            }
+        }
+    }
 
-        let volatility = log_returns
-            .map(|r| {
-                let diff = r - log_return_average;
-                diff * diff
-            })
-            .sum::<f32>()
-            .div(num_log_returns - 1.0)
-            .sqrt();
+    fn record_orders(&self, tape: &mut TradeTape) {
+        for o in &self.buy_orders {
+            tape.order(self.id, o.agent, Side::Buy, o.asset_quantity, o.limit_price);
+        }
+        for o in &self.sell_orders {
+            tape.order(
+                self.id,
+                o.agent,
+                Side::Sell,
+                o.asset_quantity,
+                o.limit_price,
+            );
+        }
+    }
 
-        self.volatility = volatility;
+    fn compute_volatility(&mut self) {
+        if let Some(volatility) = self.estimator.update(&self.price_history) {
+            self.volatility = volatility;
+        }
     }
 
     /// This function assumes that the orders are sorted
     fn execute_sell_orders<const M: usize>(
         &mut self,
-        mut amount_executed: u32,
+        amount_executed: u32,
         agents: &mut AgentCollection<M>,
         price: f32,
+        mut tape: Option<&mut TradeTape>,
     ) {
-        for so in &self.sell_orders {
+        let fills = allocate(&self.sell_orders, amount_executed, self.allocation);
+        for (so, quantity) in self.sell_orders.iter_mut().zip(fills) {
             let agent = agents.agent_mut(so.agent);
-            if amount_executed > so.asset_quantity {
-                agent.apply_sell(self.id, so.asset_quantity, price);
-                amount_executed -= so.asset_quantity;
-            } else {
-                agent.apply_sell(self.id, amount_executed, price);
-                break;
+            so.asset_quantity -= quantity;
+            agent.add_unfilled(self.id, so.asset_quantity);
+            if quantity == 0 {
+                continue;
+            }
+            agent.apply_sell(self.id, quantity, price);
+            self.fees
+                .charge(agent, price * quantity as f32, Liquidity::Auction);
+            if let Some(tape) = tape.as_deref_mut() {
+                tape.execution(self.id, so.agent, Side::Sell, quantity, price);
             }
         }
     }
@@ -103,98 +337,49 @@ impl GenoaMarket {
     /// This function assumes that the orders are sorted
     fn execute_buy_orders<const M: usize>(
         &mut self,
-        mut amount_executed: u32,
+        amount_executed: u32,
         agents: &mut AgentCollection<M>,
         price: f32,
+        mut tape: Option<&mut TradeTape>,
     ) {
-        for bo in &self.buy_orders {
+        let fills = allocate(&self.buy_orders, amount_executed, self.allocation);
+        for (bo, quantity) in self.buy_orders.iter_mut().zip(fills) {
             let agent = agents.agent_mut(bo.agent);
-            if amount_executed > bo.asset_quantity {
-                agent.apply_buy(self.id, bo.asset_quantity, price);
-                amount_executed -= bo.asset_quantity;
-            } else {
-                agent.apply_buy(self.id, amount_executed, price);
-                break;
+            bo.asset_quantity -= quantity;
+            agent.add_unfilled(self.id, bo.asset_quantity);
+            if quantity == 0 {
+                continue;
+            }
+            agent.apply_buy(self.id, quantity, price);
+            self.fees
+                .charge(agent, price * quantity as f32, Liquidity::Auction);
+            if let Some(tape) = tape.as_deref_mut() {
+                tape.execution(self.id, bo.agent, Side::Buy, quantity, price);
             }
         }
     }
 
-    pub fn plot_depth(&mut self, path: &impl AsRef<Path>) {
-        self.sort_orders();
-        let price = self.compute_price().unwrap().0;
-
-        let drawing_area = BitMapBackend::new(path, (1024, 512)).into_drawing_area();
-        drawing_area.fill(&WHITE).expect("Can't fill bitmap");
-
-        let x_range = self
-            .sell_orders
-            .iter()
-            .chain(&self.buy_orders)
-            .map(|v| (v.limit_price, v.limit_price))
-            .reduce(|(c_min, c_max), (n_min, n_max)| (c_min.min(n_min), c_max.max(n_max)))
-            .expect("no values reported");
+    /// Orders with the same limit price are brought into random order, this
+    /// function assumes that the orders are sorted.
+    fn shuffle_ties(&mut self) {
+        let same_price = |a: &GenoaOrder, b: &GenoaOrder| a.limit_price == b.limit_price;
+        for orders in [&mut self.buy_orders, &mut self.sell_orders] {
+            for ties in orders.chunk_by_mut(same_price) {
+                ties.shuffle(&mut self.rng);
+            }
+        }
+    }
 
-        let y_max_sell = self
-            .sell_orders
-            .iter()
-            .map(|so| so.asset_quantity)
-            .sum::<u32>();
-        let y_max_buy = self
-            .buy_orders
-            .iter()
-            .map(|so| so.asset_quantity)
-            .sum::<u32>();
-        let y_max = y_max_sell.max(y_max_buy);
-
-        let mut chart = ChartBuilder::on(&drawing_area)
-            .caption("Simulation Report", ("sans-serif", 50).into_font())
-            .margin(25)
-            .x_label_area_size(30)
-            .y_label_area_size(30)
-            .build_cartesian_2d(x_range.0..x_range.1, 0u32..y_max)
-            .unwrap();
-
-        chart.configure_mesh().draw().unwrap();
-
-        chart
-            .draw_series(LineSeries::new(
-                self.sell_orders.iter().scan(0, |agg, so| {
-                    *agg += so.asset_quantity;
-                    Some((so.limit_price, *agg))
-                }),
-                RED,
-            ))
-            .unwrap()
-            .label("sell depth")
-            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
-        chart
-            .draw_series(LineSeries::new(
-                self.buy_orders.iter().scan(0, |agg, so| {
-                    *agg += so.asset_quantity;
-                    Some((so.limit_price, *agg))
-                }),
-                GREEN,
-            ))
-            .unwrap()
-            .label("buy depth")
-            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], GREEN));
-
-        chart
-            .draw_series(LineSeries::new([(price, 0), (price, 10000)], BLACK))
-            .unwrap()
-            .label("price")
-            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], BLACK));
-
-        chart
-            .configure_series_labels()
-            .background_style(&WHITE.mix(0.8))
-            .border_style(&BLACK)
-            .draw()
-            .unwrap();
-    }
-
-    /// This function assumes that the orders are sorted and does not record the price
+    /// This function assumes that the orders are sorted and does not record
+    /// the price. Market orders cross every order, the price is only set by
+    /// the limit prices of the marginal orders. If both marginal orders are
+    /// market orders, the price stays the same.
     fn compute_price(&self) -> Option<(f32, u32)> {
+        let crosses = |buy: Option<f32>, sell: Option<f32>| match (buy, sell) {
+            (Some(b), Some(s)) => b >= s,
+            _ => true,
+        };
+
         let mut bos = self.buy_orders.iter();
         let bo0 = bos.next()?;
         let mut bos_sum = bo0.asset_quantity;
@@ -205,7 +390,7 @@ impl GenoaMarket {
         let mut sos_sum = so0.asset_quantity;
         let mut sos_price = so0.limit_price;
 
-        if bos_price < sos_price {
+        if !crosses(bos_price, sos_price) {
             return None; // No deal
         }
 
@@ -213,24 +398,28 @@ impl GenoaMarket {
             match bos_sum.cmp(&sos_sum) {
                 Ordering::Less => {
                     let bo = if let Some(b) = bos.next() { b } else { break };
-                    if bo.limit_price < sos_price {
+                    if !crosses(bo.limit_price, sos_price) {
                         break;
                     }
-                    bos_sum += bo.asset_quantity;
+                    bos_sum = bos_sum.saturating_add(bo.asset_quantity);
                     bos_price = bo.limit_price;
                 }
                 Ordering::Equal | Ordering::Greater => {
                     let so = if let Some(s) = sos.next() { s } else { break };
-                    if so.limit_price > bos_price {
+                    if !crosses(bos_price, so.limit_price) {
                         break;
                     }
-                    sos_sum += so.asset_quantity;
+                    sos_sum = sos_sum.saturating_add(so.asset_quantity);
                     sos_price = so.limit_price;
                 }
             }
         }
 
-        let price = (bos_price + sos_price) / 2.0;
+        let price = match (bos_price, sos_price) {
+            (Some(b), Some(s)) => self.grid.price((b + s) / 2.0),
+            (Some(p), None) | (None, Some(p)) => p,
+            (None, None) => self.price(),
+        };
         let amount_executed = sos_sum.min(bos_sum);
 
         if price <= f32::EPSILON {
@@ -240,130 +429,680 @@ impl GenoaMarket {
         Some((price, amount_executed))
     }
 
+    /// Sorts the orders by price priority, market orders come first.
     fn sort_orders(&mut self) {
-        self.buy_orders.sort_by(|a, b| {
-            b.limit_price
-                .partial_cmp(&a.limit_price)
-                .expect("found nan in buy limit_price")
-        });
-        self.sell_orders.sort_by(|a, b| {
-            a.limit_price
-                .partial_cmp(&b.limit_price)
-                .expect("found nan in sell limit_price")
-        });
-    }
-
-    fn record_price(&mut self, price: f32) {
+        self.buy_orders.sort_by(GenoaOrder::buy_priority);
+        self.sell_orders.sort_by(GenoaOrder::sell_priority);
+    }
+
+    pub fn record_price(&mut self, price: f32) {
         self.price_history.push_front(price);
         while self.price_history.len() > self.price_history_count {
             self.price_history.pop_back();
         }
     }
 
-    pub fn sell_order(&mut self, agent: AgentId, asset_quantity: u32) {
+    /// Returns profit since last time step as a percentage.
+    pub fn get_markup(&self) -> f32 {
+        let history_len = self.price_history.len();
+        (self.price_history[history_len] - self.price_history[history_len - 1])
+            / self.price_history[history_len - 1]
+    }
+}
+
+impl Market for GenoaMarket {
+    fn new(config: &Config, id: MarketId, mut rng: SimRng) -> GenoaMarket {
+        GenoaMarket {
+            id,
+            price_history: VecDeque::from(vec![config.market.initial_price; 3]),
+            price_history_count: config.market.price_history_count,
+            volatility: config.market.initial_volatility,
+            estimator: VolatilityEstimator::new(
+                &config.market.volatility,
+                config.market.initial_volatility,
+            ),
+            buy_orders: Vec::new(),
+            sell_orders: Vec::new(),
+            cancellations: Vec::new(),
+            limit_price: config.market.limit_price.clone(),
+            allocation: config.market.allocation,
+            grid: config.market.grid(id),
+            fees: Fees::new(&config.market.fees(id)),
+            breaker: CircuitBreaker::new(
+                &config.market.circuit_breaker,
+                config.market.initial_price,
+            ),
+            fundamental: Fundamental::from_config(config, id, &mut rng),
+            rng,
+        }
+    }
+
+    fn id(&self) -> MarketId {
+        self.id
+    }
+
+    fn price_history(&self) -> &VecDeque<f32> {
+        &self.price_history
+    }
+
+    fn volatility(&self) -> f32 {
+        self.volatility
+    }
+
+    fn jump_price(&mut self, size: f32) {
+        self.price_history[0] = self.grid.price(self.price_history[0] * (1.0 + size));
+    }
+
+    fn buy_order(
+        &mut self,
+        agent: AgentId,
+        cash_quantity: f32,
+        belief: f32,
+        order_type: OrderType,
+    ) {
+        if cash_quantity < f32::EPSILON {
+            return;
+        }
+        let limit_price = match order_type {
+            OrderType::Market => None,
+            _ => Some(self.sample_limit(Side::Buy, belief)),
+        };
+        // Market orders are sized with the current price, see
+        // `limit_market_buys`.
+        let gross_price = self.fees.gross_price(
+            limit_price.unwrap_or_else(|| self.price()),
+            Liquidity::Auction,
+        );
+        let asset_quantity = self.fees.affordable(cash_quantity, gross_price);
+        let order = GenoaOrder::new(agent, asset_quantity, limit_price, order_type);
+        self.push_order(Side::Buy, order);
+    }
+
+    fn sell_order(
+        &mut self,
+        agent: AgentId,
+        asset_quantity: u32,
+        belief: f32,
+        order_type: OrderType,
+    ) {
         if asset_quantity == 0 {
             return;
         }
-        let limit_price = self.price()
-            / rand_distr::Normal::new(1.01, 3.5 * self.volatility)
-                .unwrap()
-                .sample(&mut thread_rng());
-        self.sell_orders.push(GenoaOrder {
+        let limit_price = match order_type {
+            OrderType::Market => None,
+            _ => Some(self.sample_limit(Side::Sell, belief)),
+        };
+        let order = GenoaOrder::new(agent, asset_quantity, limit_price, order_type);
+        self.push_order(Side::Sell, order);
+    }
+
+    fn limit_order(&mut self, agent: AgentId, side: Side, asset_quantity: u32, limit_price: f32) {
+        self.submit(agent, side, asset_quantity, Some(limit_price));
+    }
+
+    /// Only good-till orders are carried over, they are removed before the
+    /// next clearing.
+    fn cancel_orders(&mut self, agent: AgentId) {
+        self.cancellations.push(agent);
+    }
+
+    fn revenue(&self) -> Option<Revenue> {
+        Some(self.fees.revenue()).filter(|_| self.fees.is_enabled())
+    }
+
+    fn fundamental(&self) -> Option<f32> {
+        self.fundamental.as_ref().map(Fundamental::value)
+    }
+
+    fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        Some(&self.breaker).filter(|b| b.is_enabled())
+    }
+
+    /// The depth of the orders submitted so far, including the ones carried
+    /// over from earlier steps.
+    fn depth(&self) -> Vec<DepthLevel> {
+        let mut buys = self.buy_orders.iter().collect::<Vec<_>>();
+        buys.sort_by(|a, b| GenoaOrder::buy_priority(a, b));
+        let mut sells = self.sell_orders.iter().collect::<Vec<_>>();
+        sells.sort_by(|a, b| GenoaOrder::sell_priority(a, b));
+
+        let mut levels = depth_levels(
+            Side::Buy,
+            buys.iter().map(|o| (o.limit_price, o.asset_quantity)),
+        );
+        levels.extend(depth_levels(
+            Side::Sell,
+            sells.iter().map(|o| (o.limit_price, o.asset_quantity)),
+        ));
+        levels
+    }
+
+    fn step<const M: usize>(
+        &mut self,
+        agents: &mut AgentCollection<M>,
+        mut tape: Option<&mut TradeTape>,
+    ) {
+        self.remove_cancelled(agents);
+        let mut clearing = self.clearing();
+        while let Some((price, _)) = clearing {
+            if !self.limit_market_buys(agents, price) {
+                break;
+            }
+            clearing = self.compute_price();
+        }
+        self.release_carried(agents);
+
+        if let Some(tape) = tape.as_deref_mut() {
+            self.record_orders(tape);
+        }
+
+        let (price, amount_executed) = clearing.unwrap_or_else(|| (self.price(), 0));
+
+        if self.breaker.is_enabled() && !self.breaker.allows_trading(self.price(), price) {
+            // The orders are discarded and the price stays the same.
+            if let Some(tape) = tape {
+                tape.halt(self.id, self.price());
+            }
+            self.record_price(self.price());
+            for o in self.buy_orders.iter().chain(&self.sell_orders) {
+                agents
+                    .agent_mut(o.agent)
+                    .add_unfilled(self.id, o.asset_quantity);
+            }
+        } else {
+            if self.allocation == Allocation::RandomTieBreak {
+                self.shuffle_ties();
+            }
+
+            if let Some(tape) = tape.as_deref_mut() {
+                tape.clearing(self.id, price, amount_executed);
+            }
+
+            self.record_price(price);
+
+            self.execute_buy_orders(amount_executed, agents, price, tape.as_deref_mut());
+            self.execute_sell_orders(amount_executed, agents, price, tape);
+        }
+        self.fees.end_step(agents);
+        if let Some(fundamental) = &mut self.fundamental {
+            fundamental.step();
+        }
+
+        self.compute_volatility();
+
+        self.carry_over(agents);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenoaOrder {
+    agent: AgentId,
+    asset_quantity: u32,
+    /// `None` for market orders.
+    limit_price: Option<f32>,
+    /// The amount of clearings after the next one the order takes part in.
+    clearings_left: usize,
+    /// Whether the order was carried over from an earlier step, the cash or
+    /// assets to fill it are reserved at the agent.
+    carried: bool,
+}
+
+impl GenoaOrder {
+    fn new(
+        agent: AgentId,
+        asset_quantity: u32,
+        limit_price: Option<f32>,
+        order_type: OrderType,
+    ) -> GenoaOrder {
+        GenoaOrder {
             agent,
             asset_quantity,
             limit_price,
-        })
+            clearings_left: match order_type {
+                OrderType::GoodTill(steps) => steps.saturating_sub(1),
+                _ => 0,
+            },
+            carried: false,
+        }
     }
 
-    pub fn buy_order(&mut self, agent: AgentId, cash_quantity: f32) {
-        if cash_quantity < f32::EPSILON {
-            return;
+    /// Highest limit price first, market orders before all others.
+    fn buy_priority(a: &GenoaOrder, b: &GenoaOrder) -> Ordering {
+        let price = |o: &GenoaOrder| o.limit_price.unwrap_or(f32::INFINITY);
+        price(b)
+            .partial_cmp(&price(a))
+            .expect("found nan in buy limit_price")
+    }
+
+    /// Lowest limit price first, market orders before all others.
+    fn sell_priority(a: &GenoaOrder, b: &GenoaOrder) -> Ordering {
+        let price = |o: &GenoaOrder| o.limit_price.unwrap_or(f32::NEG_INFINITY);
+        price(a)
+            .partial_cmp(&price(b))
+            .expect("found nan in sell limit_price")
+    }
+}
+
+/// The price per asset that is reserved for an order carried over to the next
+/// step, buyers also reserve the fees and taxes they might pay.
+fn reserved_price(fees: &Fees, side: Side, limit_price: Option<f32>) -> f32 {
+    let limit_price = limit_price.expect("market orders are not carried over");
+    match side {
+        Side::Buy => fees.gross_price(limit_price, Liquidity::Auction),
+        Side::Sell => limit_price,
+    }
+}
+
+/// Decides how many assets each order gets when `amount` assets are executed,
+/// the orders have to be sorted by price priority.
+fn allocate(orders: &[GenoaOrder], amount: u32, allocation: Allocation) -> Vec<u32> {
+    let mut fills = Vec::with_capacity(orders.len());
+    let mut remaining = amount;
+    for o in orders {
+        let quantity = o.asset_quantity.min(remaining);
+        remaining -= quantity;
+        fills.push(quantity);
+    }
+
+    if allocation != Allocation::ProRata {
+        return fills;
+    }
+
+    // The orders at the limit price of the last filled order, where the
+    // amount ran out, share what is left after all better orders are filled.
+    let marginal = match fills.iter().rposition(|&f| f > 0) {
+        Some(m) => m,
+        None => return fills,
+    };
+    let marginal_price = orders[marginal].limit_price;
+    let start = orders[..marginal]
+        .iter()
+        .rposition(|o| o.limit_price != marginal_price)
+        .map_or(0, |i| i + 1);
+    let end = orders[marginal..]
+        .iter()
+        .position(|o| o.limit_price != marginal_price)
+        .map_or(orders.len(), |i| marginal + i);
+
+    let ties = &orders[start..end];
+    let total = ties.iter().map(|o| o.asset_quantity as u64).sum::<u64>();
+    let mut left = fills[start..end].iter().sum::<u32>();
+    let shared = left as u64;
+    for (fill, o) in fills[start..end].iter_mut().zip(ties) {
+        *fill = (o.asset_quantity as u64 * shared / total) as u32;
+        left -= *fill;
+    }
+    // Hand out what is left after rounding down, one asset per order.
+    for (fill, o) in fills[start..end].iter_mut().zip(ties) {
+        if left == 0 {
+            break;
         }
-        let limit_price = self.price()
-            * rand_distr::Normal::new(1.01, 3.5 * self.volatility)
+        if *fill < o.asset_quantity {
+            *fill += 1;
+            left -= 1;
+        }
+    }
+    fills
+}
+
+/// The smallest factor of the Student-t limit price model, the tails of the
+/// distribution would otherwise lead to negative or infinite limit prices.
+const MIN_STUDENT_T_FACTOR: f32 = 0.01;
+
+/// Sample a limit price around the current price as in the Genoa market paper,
+/// buyers are willing to pay a bit more, sellers accept a bit less. The belief
+/// is the state of the agent for the market.
+pub fn sample_limit_price(
+    model: &LimitPriceConfig,
+    side: Side,
+    price: f32,
+    volatility: f32,
+    belief: f32,
+    rng: &mut SimRng,
+) -> f32 {
+    let sd = model.volatility_factor * volatility;
+    let factor = match model.model {
+        LimitPriceModel::Genoa => rand_distr::Normal::new(model.mean, sd).unwrap().sample(rng),
+        LimitPriceModel::Belief => {
+            let strength = match side {
+                Side::Buy => belief,
+                Side::Sell => 1.0 - belief,
+            };
+            let mean = 1.0 + (model.mean - 1.0) * strength;
+            rand_distr::Normal::new(mean, sd).unwrap().sample(rng)
+        }
+        LimitPriceModel::StudentT => {
+            let t = rand_distr::StudentT::new(model.degrees_of_freedom)
                 .unwrap()
-                .sample(&mut thread_rng());
-        self.buy_orders.push(GenoaOrder {
-            agent,
-            limit_price,
-            asset_quantity: (cash_quantity / limit_price) as u32,
+                .sample(rng);
+            (model.mean + sd * t).max(MIN_STUDENT_T_FACTOR)
+        }
+    };
+    match side {
+        Side::Buy => price * factor,
+        Side::Sell => price / factor,
+    }
+}
+
+/// Compute the sample standard deviation of the log returns of a price
+/// history, `None` if there are not enough prices.
+pub fn log_return_volatility(price_history: &VecDeque<f32>) -> Option<f32> {
+    if price_history.len() < 3 {
+        // We need at least three values to compute the log returns
+        return None;
+    }
+
+    let num_log_returns = (price_history.len() - 1) as f32;
+
+    let log_returns = price_history
+        .iter()
+        .zip(price_history.iter().skip(1))
+        .map(|(n, np1)| (np1 / n).ln());
+
+    let log_return_average = log_returns.clone().sum::<f32>() / num_log_returns;
+
+    let volatility = log_returns
+        .map(|r| {
+            let diff = r - log_return_average;
+            diff * diff
         })
+        .sum::<f32>()
+        .div(num_log_returns - 1.0)
+        .sqrt();
+
+    Some(volatility)
+}
+
+/// A market using any of the available mechanisms, see [`MarketMechanism`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AnyMarket {
+    Genoa(GenoaMarket),
+    OrderBook(OrderBookMarket),
+}
+
+macro_rules! dispatch {
+    ($self:expr, $m:ident => $e:expr) => {
+        match $self {
+            AnyMarket::Genoa($m) => $e,
+            AnyMarket::OrderBook($m) => $e,
+        }
+    };
+}
+
+impl Market for AnyMarket {
+    fn new(config: &Config, id: MarketId, rng: SimRng) -> AnyMarket {
+        match config.market.mechanism(id) {
+            MarketMechanism::Genoa => AnyMarket::Genoa(GenoaMarket::new(config, id, rng)),
+            MarketMechanism::OrderBook => {
+                AnyMarket::OrderBook(OrderBookMarket::new(config, id, rng))
+            }
+        }
     }
 
-    /// Returns profit since last time step as a percentage.
-    pub fn get_markup(&self) -> f32 {
-        let history_len = self.price_history.len();
-        (self.price_history[history_len] - self.price_history[history_len - 1])
-            / self.price_history[history_len - 1]
+    fn id(&self) -> MarketId {
+        dispatch!(self, m => m.id())
     }
 
-    pub fn volatility(&self) -> f32 {
-        self.volatility
+    fn price_history(&self) -> &VecDeque<f32> {
+        dispatch!(self, m => m.price_history())
     }
 
-    pub fn price(&self) -> f32 {
-        self.price_history[0]
+    fn volatility(&self) -> f32 {
+        dispatch!(self, m => m.volatility())
     }
 
-    pub fn price_ago(&self, delay: usize) -> f32 {
-        self.price_history[delay]
+    fn spread(&self) -> Option<f32> {
+        dispatch!(self, m => m.spread())
     }
 
-    /// Get the genoa market's id.
-    pub fn id(&self) -> MarketId {
-        self.id
+    fn buy_order(
+        &mut self,
+        agent: AgentId,
+        cash_quantity: f32,
+        belief: f32,
+        order_type: OrderType,
+    ) {
+        dispatch!(self, m => m.buy_order(agent, cash_quantity, belief, order_type))
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct GenoaOrder {
-    agent: AgentId,
-    asset_quantity: u32,
-    limit_price: f32,
+    fn sell_order(
+        &mut self,
+        agent: AgentId,
+        asset_quantity: u32,
+        belief: f32,
+        order_type: OrderType,
+    ) {
+        dispatch!(self, m => m.sell_order(agent, asset_quantity, belief, order_type))
+    }
+
+    fn limit_order(&mut self, agent: AgentId, side: Side, asset_quantity: u32, limit_price: f32) {
+        dispatch!(self, m => m.limit_order(agent, side, asset_quantity, limit_price))
+    }
+
+    fn cancel_orders(&mut self, agent: AgentId) {
+        dispatch!(self, m => m.cancel_orders(agent))
+    }
+
+    fn revenue(&self) -> Option<Revenue> {
+        dispatch!(self, m => m.revenue())
+    }
+
+    fn fundamental(&self) -> Option<f32> {
+        dispatch!(self, m => m.fundamental())
+    }
+
+    fn depth(&self) -> Vec<DepthLevel> {
+        dispatch!(self, m => m.depth())
+    }
+
+    fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        dispatch!(self, m => m.circuit_breaker())
+    }
+
+    fn jump_price(&mut self, size: f32) {
+        dispatch!(self, m => m.jump_price(size))
+    }
+
+    fn step<const M: usize>(
+        &mut self,
+        agents: &mut AgentCollection<M>,
+        tape: Option<&mut TradeTape>,
+    ) {
+        dispatch!(self, m => m.step(agents, tape))
+    }
 }
 
 #[cfg(test)]
 pub mod test {
-    use core::panic;
+    use rand::SeedableRng;
 
     use super::*;
 
     #[test]
     fn test_genoa_market_step() {
-        let mut market = GenoaMarket::new(&Config::default(), 0);
-        market.sell_orders.push(GenoaOrder {
-            agent: 0,
-            asset_quantity: 2,
-            limit_price: 0.9,
-        });
-        market.sell_orders.push(GenoaOrder {
-            agent: 1,
-            asset_quantity: 8,
-            limit_price: 0.95,
-        });
-        market.sell_orders.push(GenoaOrder {
-            agent: 2,
-            asset_quantity: 1,
-            limit_price: 0.8,
-        });
-        market.buy_orders.push(GenoaOrder {
-            agent: 3,
-            asset_quantity: 2,
-            limit_price: 1.1,
-        });
-        market.buy_orders.push(GenoaOrder {
-            agent: 4,
-            asset_quantity: 2,
-            limit_price: 1.15,
-        });
-        market.buy_orders.push(GenoaOrder {
-            agent: 5,
-            asset_quantity: 5,
-            limit_price: 1.05,
-        });
-        println!("{:?}", &market);
+        let mut market = GenoaMarket::new(&Config::default(), 0, SimRng::seed_from_u64(0));
+        market.limit_order(0, Side::Sell, 2, 0.9);
+        market.limit_order(1, Side::Sell, 8, 0.95);
+        market.limit_order(2, Side::Sell, 1, 0.8);
+        market.limit_order(3, Side::Buy, 2, 1.1);
+        market.limit_order(4, Side::Buy, 2, 1.15);
+        market.limit_order(5, Side::Buy, 5, 1.05);
         market.sort_orders();
-        println!("{:?}", &market);
-        panic!()
+
+        let buy_limits = market
+            .buy_orders
+            .iter()
+            .map(|o| o.limit_price.unwrap())
+            .collect::<Vec<_>>();
+        let sell_limits = market
+            .sell_orders
+            .iter()
+            .map(|o| o.limit_price.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(buy_limits, [1.15, 1.1, 1.05]);
+        assert_eq!(sell_limits, [0.8, 0.9, 0.95]);
+
+        let (price, amount_executed) = market.compute_price().unwrap();
+        assert_eq!(amount_executed, 9);
+        assert!((price - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_fees_only_on_executions() {
+        let mut config = Config::default();
+        config.agent.agent_count = 2;
+        config.market.fees = vec![crate::config::FeeConfig {
+            fixed: 1.0,
+            ..Default::default()
+        }];
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let mut market = GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0));
+        let cash = agents.total_cash();
+
+        // Without a seller nothing is executed, so nobody pays the fixed fee.
+        market.limit_order(0, Side::Buy, 5, 100.0);
+        market.step(&mut agents, None);
+        assert_eq!(agents.total_cash(), cash);
+    }
+
+    #[test]
+    fn test_allocation() {
+        let order = |asset_quantity, limit_price| {
+            GenoaOrder::new(0, asset_quantity, Some(limit_price), OrderType::Limit)
+        };
+        let orders = [order(5, 1.2), order(4, 1.0), order(6, 1.0), order(3, 0.9)];
+
+        assert_eq!(
+            allocate(&orders, 10, Allocation::PricePriority),
+            [5, 4, 1, 0]
+        );
+        assert_eq!(allocate(&orders, 10, Allocation::ProRata), [5, 2, 3, 0]);
+        assert_eq!(allocate(&orders, 8, Allocation::ProRata), [5, 2, 1, 0]);
+        // The amount runs out exactly at the end of an order of the tie.
+        assert_eq!(allocate(&orders, 9, Allocation::ProRata), [5, 2, 2, 0]);
+        assert_eq!(allocate(&orders, 15, Allocation::ProRata), [5, 4, 6, 0]);
+        assert_eq!(allocate(&orders, 0, Allocation::ProRata), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_limit_price_models() {
+        let mut rng = SimRng::seed_from_u64(0);
+        let mut model = LimitPriceConfig {
+            mean: 1.1,
+            ..LimitPriceConfig::default()
+        };
+        let mut sample = |model: &LimitPriceConfig, side, volatility, belief| {
+            sample_limit_price(model, side, 100.0, volatility, belief, &mut rng)
+        };
+
+        assert!((sample(&model, Side::Buy, 0.0, 0.0) - 110.0).abs() < 1e-3);
+        assert!((sample(&model, Side::Sell, 0.0, 0.0) - 100.0 / 1.1).abs() < 1e-3);
+
+        model.model = LimitPriceModel::Belief;
+        assert!((sample(&model, Side::Buy, 0.0, 0.5) - 105.0).abs() < 1e-3);
+        assert!((sample(&model, Side::Sell, 0.0, 1.0) - 100.0).abs() < 1e-3);
+
+        model.model = LimitPriceModel::StudentT;
+        assert!((sample(&model, Side::Buy, 0.0, 0.0) - 110.0).abs() < 1e-3);
+        assert!(sample(&model, Side::Buy, 0.01, 0.0).is_finite());
+
+        // Even a huge volatility gives positive and finite prices.
+        for _ in 0..1000 {
+            for side in [Side::Buy, Side::Sell] {
+                let price = sample(&model, side, 100.0, 0.0);
+                assert!(price > 0.0 && price.is_finite(), "{}", price);
+            }
+        }
+    }
+
+    #[test]
+    fn test_market_orders() {
+        let mut market = GenoaMarket::new(&Config::default(), 0, SimRng::seed_from_u64(0));
+        market.submit(0, Side::Buy, 5, None);
+        market.limit_order(1, Side::Sell, 3, 0.9);
+        market.limit_order(2, Side::Sell, 4, 1.0);
+        let depth = market
+            .depth()
+            .iter()
+            .map(|l| (l.side, l.price, l.quantity))
+            .collect::<Vec<_>>();
+        assert_eq!(depth, [(Side::Sell, 0.9, 3), (Side::Sell, 1.0, 7)]);
+        assert_eq!(market.clearing(), Some((1.0, 5)));
+
+        market.clear_orders();
+        market.submit(0, Side::Sell, 10, None);
+        market.limit_order(1, Side::Buy, 4, 1.2);
+        assert_eq!(market.clearing(), Some((1.2, 4)));
+
+        // Without any limit price the price stays the same.
+        market.clear_orders();
+        market.submit(0, Side::Sell, 10, None);
+        market.submit(1, Side::Buy, 4, None);
+        assert_eq!(market.clearing(), Some((market.price(), 4)));
+    }
+
+    #[test]
+    fn test_grid() {
+        let grid = GridConfig {
+            tick_size: Some(0.05),
+            lot_size: 10,
+        };
+        assert!((grid.limit_price(Side::Buy, 100.07) - 100.05).abs() < 1e-4);
+        assert!((grid.limit_price(Side::Sell, 100.07) - 100.1).abs() < 1e-4);
+        assert!((grid.limit_price(Side::Sell, 100.05) - 100.05).abs() < 1e-4);
+        assert!((grid.limit_price(Side::Buy, 0.01) - 0.05).abs() < 1e-6);
+        assert_eq!(grid.limit_price(Side::Buy, f32::INFINITY), f32::INFINITY);
+        assert_eq!(grid.lots(29), 20);
+
+        let mut config = Config::default();
+        config.agent.agent_count = 4;
+        config.agent.fundamentalist_count = 0;
+        config.market.grids = vec![grid];
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let mut market = GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0));
+
+        market.limit_order(0, Side::Buy, 15, 100.12);
+        market.limit_order(1, Side::Sell, 9, 99.0);
+        market.limit_order(2, Side::Sell, 25, 99.93);
+        market.step(&mut agents, None);
+        // The midpoint of 100.10 and 99.95 is rounded to the nearest tick.
+        assert!((market.price() - 100.05).abs() < 1e-4);
+        assert_eq!(agents.agent(0).assets[0], 40);
+        assert_eq!(agents.agent(1).assets[0], 30);
+        assert_eq!(agents.agent(2).assets[0], 20);
+    }
+
+    #[test]
+    fn test_order_types_in_step() {
+        let mut config = Config::default();
+        config.agent.agent_count = 2;
+        config.agent.fundamentalist_count = 0;
+        config.agent.initial_cash = crate::config::Distribution::static_value(1000.0);
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let mut market = GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0));
+
+        // Market buy orders are reduced to what the agent can pay.
+        let order = GenoaOrder::new(0, 100, None, OrderType::Market);
+        market.push_order(Side::Buy, order);
+        market.limit_order(1, Side::Sell, 30, 100.0);
+        market.step(&mut agents, None);
+        assert_eq!(market.price(), 100.0);
+        assert_eq!(agents.agent(0).assets[0], 40);
+        assert_eq!(agents.agent(0).cash, 0.0);
+        assert_eq!(agents.agent(1).unfilled[0], 20);
+
+        // The remainder of a good-till order is kept with its cash reserved.
+        let order = GenoaOrder::new(1, 10, Some(90.0), OrderType::GoodTill(2));
+        market.push_order(Side::Buy, order);
+        market.step(&mut agents, None);
+        assert_eq!(market.buy_orders.len(), 1);
+        assert_eq!(agents.agent(1).reserved_cash, 900.0);
+
+        market.limit_order(0, Side::Sell, 4, 80.0);
+        market.step(&mut agents, None);
+        assert_eq!(market.price(), 85.0);
+        assert_eq!(agents.agent(1).assets[0], 24);
+        assert_eq!(agents.agent(1).reserved_cash, 0.0);
+        assert!(market.buy_orders.is_empty());
     }
 }
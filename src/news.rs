@@ -0,0 +1,179 @@
+use rand::{Rng, SeedableRng};
+use rand_distr::Poisson;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent::AgentCollection,
+    config::{Config, NewsConfig},
+    market::{Market, MarketId},
+    report::Reporter,
+    simulation::SimRng,
+};
+
+/// Applies the shocks of the news schedule, see [`NewsConfig`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct News {
+    schedule: Vec<NewsConfig>,
+    rng: SimRng,
+}
+
+impl News {
+    pub fn new(schedule: Vec<NewsConfig>, rng: SimRng) -> News {
+        News { schedule, rng }
+    }
+
+    /// The news schedule of the config, if it has any entries. The random
+    /// number generator is only used if it does. The markets of the news are
+    /// checked by [`Config::validate`].
+    pub fn from_config(config: &Config, rng: &mut SimRng) -> Option<News> {
+        (!config.news.is_empty())
+            .then(|| News::new(config.news.clone(), SimRng::seed_from_u64(rng.gen())))
+    }
+
+    /// How often a shock happens at a step.
+    fn occurrences(rng: &mut SimRng, news: &NewsConfig, step: usize) -> usize {
+        match news.step {
+            Some(s) => (s == step) as usize,
+            None if news.rate > 0.0 => {
+                let poisson = Poisson::new(news.rate).expect("can't sample news with this rate");
+                rng.sample::<f32, _>(poisson) as usize
+            }
+            None => 0,
+        }
+    }
+
+    /// Apply the shocks of a step, this is called before the agents update
+    /// their beliefs. Every shock is reported as an event.
+    pub fn apply<Mk: Market, const M: usize>(
+        &mut self,
+        step: usize,
+        agents: &mut AgentCollection<M>,
+        markets: &mut [Mk],
+        reporter: &mut Reporter,
+    ) {
+        for news in &self.schedule {
+            for _ in 0..Self::occurrences(&mut self.rng, news, step) {
+                let affected = if news.markets.is_empty() {
+                    (0..markets.len()).collect::<Vec<MarketId>>()
+                } else {
+                    news.markets.clone()
+                };
+
+                let mut effects = Vec::new();
+                if news.agent_fraction > 0.0 && news.state_shift != 0.0 {
+                    let shifted = agents.shift_states(
+                        &affected,
+                        news.agent_fraction,
+                        news.state_shift,
+                        &mut self.rng,
+                    );
+                    effects.push(format!(
+                        "state of {} traders shifted by {}",
+                        shifted, news.state_shift
+                    ));
+                }
+                if news.fundamentalist_fraction > 0.0 {
+                    let flipped = agents.flip_fundamentalists(
+                        &affected,
+                        news.fundamentalist_fraction,
+                        &mut self.rng,
+                    );
+                    effects.push(format!("{} fundamentalists flipped", flipped));
+                }
+                if news.price_jump != 0.0 {
+                    for &m in &affected {
+                        markets[m].jump_price(news.price_jump);
+                    }
+                    effects.push(format!("price moved by {}%", news.price_jump * 100.0));
+                }
+
+                reporter.report_event(format!(
+                    "{} in markets {:?}: {}",
+                    news.description,
+                    affected,
+                    if effects.is_empty() {
+                        "no effect".to_string()
+                    } else {
+                        effects.join(", ")
+                    }
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::market::GenoaMarket;
+
+    #[test]
+    fn test_scheduled_news() {
+        let mut config = Config::default();
+        config.market.market_count = 2;
+        config.agent.agent_count = 100;
+        config.agent.fundamentalist_count = 10;
+        config.news = vec![NewsConfig {
+            step: Some(3),
+            markets: vec![1],
+            agent_fraction: 0.5,
+            state_shift: -1.0,
+            fundamentalist_fraction: 1.0,
+            price_jump: -0.2,
+            ..NewsConfig::default()
+        }];
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let mut markets = (0..2)
+            .map(|i| GenoaMarket::new(&config, i, SimRng::seed_from_u64(0)))
+            .collect::<Vec<_>>();
+        let mut news = News::from_config(&config, &mut SimRng::seed_from_u64(0)).unwrap();
+        let mut reporter = Reporter::new();
+        let states = |agents: &AgentCollection<5>, m: MarketId| {
+            (0..110)
+                .map(|i| agents.influence_at_market(i, m))
+                .collect::<Vec<_>>()
+        };
+        let before = [states(&agents, 0), states(&agents, 1)];
+
+        reporter.set_step(2);
+        news.apply(2, &mut agents, &mut markets, &mut reporter);
+        assert_eq!(states(&agents, 1), before[1]);
+
+        reporter.set_step(3);
+        news.apply(3, &mut agents, &mut markets, &mut reporter);
+        assert_eq!(states(&agents, 0), before[0]);
+        let after = states(&agents, 1);
+        let pessimistic = after[..100].iter().filter(|&&s| s == 0.0).count();
+        assert!(pessimistic >= 50);
+        for (a, b) in after[100..].iter().zip(&before[1][100..]) {
+            assert_eq!(*a, 1.0 - b);
+        }
+        assert_eq!(markets[0].price(), 100.0);
+        assert!((markets[1].price() - 80.0).abs() < 1e-3);
+        assert_eq!(reporter.events().len(), 1);
+        assert_eq!(reporter.events()[0].0, 3);
+    }
+
+    #[test]
+    fn test_random_news() {
+        let config = Config {
+            news: vec![NewsConfig {
+                rate: 0.1,
+                price_jump: 0.01,
+                ..NewsConfig::default()
+            }],
+            ..Config::default()
+        };
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        let mut markets = vec![GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0))];
+        let mut news = News::from_config(&config, &mut SimRng::seed_from_u64(0)).unwrap();
+        let mut reporter = Reporter::new();
+        for step in 0..1000 {
+            news.apply(step, &mut agents, &mut markets, &mut reporter);
+        }
+        let count = reporter.events().len();
+        assert!((50..150).contains(&count), "{} shocks", count);
+        let expected = 100.0 * 1.01f32.powi(count as i32);
+        assert!((markets[0].price() - expected).abs() < 0.01 * expected);
+    }
+}
@@ -1,5 +1,11 @@
 use std::{
-    cmp::Ordering, collections::HashMap, fmt::Write, hash::Hash, io::Write as IoWrite, path::Path,
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display, Formatter},
+    hash::Hash,
+    io::Write,
+    path::Path,
 };
 
 use plotters::{
@@ -7,47 +13,59 @@ use plotters::{
     prelude::{ChartBuilder, DrawingArea, DrawingBackend, LineSeries},
     style::{Color, IntoFont, BLACK},
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FileLocation {
-    pub file: &'static str,
+    pub file: Cow<'static, str>,
     pub line: u32,
     pub col: u32,
 }
 
-impl ToString for FileLocation {
-    fn to_string(&self) -> String {
-        format!("{}:{}:{}", self.file, self.line, self.col)
+impl Display for FileLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
     }
 }
 
 /// When reporting a value, a target is given, this target contains information
 /// about the origin of the report, as well as how the value can be used.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ReporterTarget {
     pub origin: Option<FileLocation>,
-    pub description: Option<&'static str>,
+    pub description: Option<Cow<'static, str>>,
     pub index: Option<u32>,
 }
 
-impl ToString for ReporterTarget {
-    fn to_string(&self) -> String {
-        let mut label = self
-            .description
-            .map(ToOwned::to_owned)
-            .or_else(|| self.origin.clone()?.to_string().into())
-            .unwrap_or_else(|| "no-label".into());
+impl Display for ReporterTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (&self.description, &self.origin) {
+            (Some(d), _) => write!(f, "{}", d)?,
+            (None, Some(o)) => write!(f, "{}", o)?,
+            (None, None) => write!(f, "no-label")?,
+        }
         if let Some(i) = self.index {
-            write!(&mut label, "[{}]", i).unwrap();
+            write!(f, "[{}]", i)?;
         }
-        label
+        Ok(())
     }
 }
 
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct Reporter {
     current_step: usize,
+    /// Steps before this one are not recorded.
+    burn_in: usize,
+    /// Only every n-th step after the burn in is recorded, every step if zero.
+    sample_every: usize,
+    /// The values of the recorded steps, see [`Self::row`].
     per_step: HashMap<ReporterTarget, Vec<f64>>,
+    /// Values describing the whole run, like the seed. These are written as
+    /// '#' comment lines above the header of the csv and into the caption of
+    /// the plot.
+    metadata: BTreeMap<String, String>,
+    /// Things that happened during the run, with the step they happened in.
+    events: Vec<(usize, String)>,
 }
 
 impl Reporter {
@@ -55,25 +73,91 @@ impl Reporter {
         Self::default()
     }
 
+    /// Discard the values and events of the first `burn_in` steps, and only
+    /// record the values of every `sample_every`-th step after that. Events
+    /// are recorded in every step after the burn in.
+    pub fn set_sampling(&mut self, burn_in: usize, sample_every: usize) {
+        self.burn_in = burn_in;
+        self.sample_every = sample_every;
+    }
+
+    fn sample_every(&self) -> usize {
+        self.sample_every.max(1)
+    }
+
+    /// The row of the values of a step, if the step is recorded.
+    fn row(&self, step: usize) -> Option<usize> {
+        let since_burn_in = step.checked_sub(self.burn_in)?;
+        since_burn_in
+            .is_multiple_of(self.sample_every())
+            .then(|| since_burn_in / self.sample_every())
+    }
+
+    /// The step whose values are stored in a row.
+    fn step_of_row(&self, row: usize) -> usize {
+        self.burn_in + row * self.sample_every()
+    }
+
     pub fn report_num(&mut self, target: ReporterTarget, value: f64) {
+        let row = match self.row(self.current_step) {
+            Some(row) => row,
+            None => return,
+        };
         let vec = self.per_step.entry(target).or_default();
-        match vec.len().cmp(&self.current_step) {
+        match vec.len().cmp(&row) {
             Ordering::Equal => vec.push(value),
             Ordering::Less => {
-                vec.extend(std::iter::repeat(f64::NAN).take(self.current_step - vec.len()));
+                vec.extend(std::iter::repeat_n(f64::NAN, row - vec.len()));
                 vec.push(value);
             }
-            Ordering::Greater => vec[self.current_step] = value,
+            Ordering::Greater => vec[row] = value,
+        }
+    }
+
+    pub fn set_metadata(&mut self, key: impl Into<String>, value: impl ToString) {
+        self.metadata.insert(key.into(), value.to_string());
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    /// Record an event in the current step, unless it is part of the burn
+    /// in.
+    pub fn report_event(&mut self, description: impl Into<String>) {
+        if self.current_step >= self.burn_in {
+            self.events.push((self.current_step, description.into()));
         }
     }
 
+    pub fn events(&self) -> &[(usize, String)] {
+        &self.events
+    }
+
     pub fn render_chart<DB>(&self, da: DrawingArea<DB, Shift>)
     where
         DB: DrawingBackend,
     {
+        let da = if self.metadata.is_empty() {
+            da
+        } else {
+            let caption = self
+                .metadata
+                .iter()
+                .map(|(k, v)| format!("{} = {}", k, v))
+                .collect::<Vec<_>>()
+                .join(", ");
+            da.titled(&caption, ("monospace", 30).into_font())
+                .expect("Can't draw caption")
+        };
+
         let chart_count = self.per_step.len();
+        if chart_count == 0 {
+            return;
+        }
         let chart_width = (chart_count as f64).sqrt().round() as usize;
-        let chart_height = chart_count / chart_width + (chart_count % chart_width != 0) as usize;
+        let chart_height =
+            chart_count / chart_width + !chart_count.is_multiple_of(chart_width) as usize;
         let das = da.split_evenly((chart_width, chart_height));
 
         let mut cols = self.per_step.keys().collect::<Vec<_>>();
@@ -83,7 +167,7 @@ impl Reporter {
             let label = target.to_string();
             let color = BLACK.mix(0.7);
 
-            let max_step = series.len();
+            let steps = self.step_of_row(0)..self.step_of_row(series.len());
 
             let y_range = series
                 .iter()
@@ -100,7 +184,7 @@ impl Reporter {
                 .margin_bottom(0)
                 .x_label_area_size(25)
                 .y_label_area_size(50)
-                .build_cartesian_2d(0..max_step, y_range.0..y_range.1)
+                .build_cartesian_2d(steps, y_range.0..y_range.1)
                 .unwrap();
 
             chart
@@ -111,7 +195,10 @@ impl Reporter {
 
             chart
                 .draw_series(LineSeries::new(
-                    series.iter().enumerate().map(|(i, v)| (i, *v)),
+                    series
+                        .iter()
+                        .enumerate()
+                        .map(|(i, v)| (self.step_of_row(i), *v)),
                     color,
                 ))
                 .unwrap();
@@ -127,25 +214,31 @@ impl Reporter {
         }
     }
 
+    /// Write the reported values with one row per step. The metadata of the
+    /// run is written once, as comment lines starting with `#` above the
+    /// header.
     pub fn write_csv(&self, path: impl AsRef<Path>) {
         let mut cols = self.per_step.keys().collect::<Vec<_>>();
         cols.sort_by_key(|k| k.to_string());
 
         let mut file = std::fs::File::create(path).expect("can't create csv file");
 
+        for (k, v) in &self.metadata {
+            writeln!(file, "# {}: {}", k, v).unwrap();
+        }
         write!(file, "step").unwrap();
         for c in &cols {
-            write!(file, ",{}", c.to_string()).unwrap();
+            write!(file, ",{}", c).unwrap();
         }
 
-        for step in 0.. {
+        for row in 0.. {
             let mut stop = true;
 
             writeln!(file).unwrap();
-            write!(file, "{}", step).unwrap();
+            write!(file, "{}", self.step_of_row(row)).unwrap();
 
             for c in &cols {
-                let val = self.per_step[c].get(step).copied();
+                let val = self.per_step[c].get(row).copied();
                 if val.is_some() {
                     stop = false;
                 }
@@ -158,6 +251,19 @@ impl Reporter {
         }
     }
 
+    /// Write all events with their step, one per line. Nothing is written if
+    /// there were no events.
+    pub fn write_events(&self, path: impl AsRef<Path>) {
+        if self.events.is_empty() {
+            return;
+        }
+
+        let mut file = std::fs::File::create(path).expect("can't create events file");
+        for (step, description) in &self.events {
+            writeln!(file, "{}: {}", step, description).unwrap();
+        }
+    }
+
     pub(crate) fn set_step(&mut self, step: usize) {
         self.current_step = step;
     }
@@ -176,11 +282,11 @@ macro_rules! report {
     ("internal", $r:expr, $desc:expr, $idx:expr, $val:expr) => {{
         let target = crate::report::ReporterTarget {
             origin: Some(crate::report::FileLocation {
-                file: file!(),
+                file: std::borrow::Cow::Borrowed(file!()),
                 line: line!(),
                 col: column!(),
             }),
-            description: $desc,
+            description: $desc.map(std::borrow::Cow::Borrowed),
             index: $idx,
         };
         $r.report_num(target, $val);
@@ -194,6 +300,22 @@ pub mod test {
 
     use super::*;
 
+    #[test]
+    fn test_sampling() {
+        let mut r = Reporter::new();
+        r.set_sampling(5, 3);
+        for step in 0..15 {
+            r.set_step(step);
+            report!(r, "value", step as f64);
+            r.report_event(step.to_string());
+        }
+        let values = r.per_step.values().next().unwrap();
+        assert_eq!(values, &[5.0, 8.0, 11.0, 14.0]);
+        assert_eq!(r.step_of_row(3), 14);
+        assert_eq!(r.events().len(), 10);
+        assert_eq!(r.events()[0].0, 5);
+    }
+
     #[test]
     fn test_csv_plot() {
         let r = Reporter::new();
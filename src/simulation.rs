@@ -1,59 +1,167 @@
-use rand::{prelude::SliceRandom, thread_rng};
+use rand::{prelude::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     agent::AgentCollection,
-    config::Config,
-    market::GenoaMarket,
+    audit::Audit,
+    config::{Config, StrategyKind},
+    depth::{Depth, DepthCapture},
+    market::{AnyMarket, Market, MarketId},
+    news::News,
     report::{report, Reporter},
+    tape::TradeTape,
 };
 
 pub const AGENT_PER_MARKET_INLINE_THRESHOLD: usize = 5;
 
-#[derive(Debug, Clone)]
-pub struct Simulation {
-    markets: Vec<GenoaMarket>,
+/// The random number generator used throughout the simulation. The simulation
+/// owns one, seeded from the run seed, and every component that needs
+/// randomness gets its own generator seeded from that one.
+pub type SimRng = ChaCha8Rng;
+
+/// A simulation of agents trading on markets of type `Mk`. By default every
+/// market can use any of the built-in mechanisms, as configured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Simulation<Mk = AnyMarket> {
+    markets: Vec<Mk>,
     agents: AgentCollection<AGENT_PER_MARKET_INLINE_THRESHOLD>,
+    rng: SimRng,
+    /// Without an audit, agents that spend more than they have abort the run.
+    audit: Option<Audit>,
+    depth: Option<DepthCapture>,
+    news: Option<News>,
 }
 
-impl Simulation {
-    pub fn new(config: &Config) -> Simulation {
+impl<Mk: Market> Simulation<Mk> {
+    pub fn new(config: &Config, seed: u64) -> Simulation<Mk> {
+        let mut rng = SimRng::seed_from_u64(seed);
         Simulation {
+            agents: AgentCollection::new(config, SimRng::seed_from_u64(rng.gen())),
             markets: (0..config.market.market_count)
-                .map(|i| GenoaMarket::new(config, i))
+                .map(|i| Mk::new(config, i, SimRng::seed_from_u64(rng.gen())))
                 .collect(),
-            agents: AgentCollection::new(config),
+            news: News::from_config(config, &mut rng),
+            rng,
+            audit: None,
+            depth: None,
+        }
+    }
+
+    /// Capture the depth of markets before their orders are executed, see
+    /// [`DepthCapture`].
+    pub fn capture_depth(&mut self, capture: DepthCapture) {
+        self.depth = Some(capture);
+    }
+
+    /// The depths captured since the last call.
+    pub fn take_depths(&mut self) -> Vec<Depth> {
+        self.depth
+            .as_mut()
+            .map(DepthCapture::take)
+            .unwrap_or_default()
+    }
+
+    /// Check the accounting after every step from now on, see [`Audit`].
+    pub fn enable_audit(&mut self) {
+        if self.audit.is_none() {
+            self.audit = Some(Audit::new(&self.agents, &self.markets));
         }
     }
 
+    pub fn audit(&self) -> Option<&Audit> {
+        self.audit.as_ref()
+    }
+
     pub fn agents(&self) -> &AgentCollection<AGENT_PER_MARKET_INLINE_THRESHOLD> {
         &self.agents
     }
 
-    pub fn markets(&self) -> &[GenoaMarket] {
+    pub fn markets(&self) -> &[Mk] {
         &self.markets[..]
     }
 
-    pub fn step(&mut self, step: usize, reporter: &mut Reporter) {
+    pub fn step(&mut self, step: usize, reporter: &mut Reporter, mut tape: Option<&mut TradeTape>) {
+        if let Some(news) = &mut self.news {
+            news.apply(step, &mut self.agents, &mut self.markets, reporter);
+        }
+
         // just runs dga
         self.agents.step(&self.markets[..], step);
 
         // Runs market logic (we shuffle the market access)
         let mut markets = self.markets.iter_mut().collect::<Vec<_>>();
-        markets.shuffle(&mut thread_rng());
+        markets.shuffle(&mut self.rng);
 
+        let mut insolvent = Vec::new();
         for m in markets {
             self.agents.step_market(m);
-            m.step(&mut self.agents);
+            if let Some(capture) = &mut self.depth {
+                capture.capture(step, &*m);
+            }
+            m.step(&mut self.agents, tape.as_deref_mut());
+
+            let overdrawn = self.agents.take_overdrawn();
+            if self.agents.has_population_changes() {
+                insolvent.extend_from_slice(&overdrawn);
+            }
+            match &mut self.audit {
+                Some(audit) => audit.overdrawn(step, m.id(), overdrawn),
+                None => assert!(
+                    overdrawn.is_empty() || self.agents.has_population_changes(),
+                    "Agents {:?} ran out of cash or assets in market {}",
+                    overdrawn,
+                    m.id()
+                ),
+            }
+        }
+
+        // Bankrupt agents leave and new ones enter
+        let bankrupt = self.agents.update_population(&self.markets[..], &insolvent);
+        for m in &mut self.markets {
+            for &agent in &bankrupt {
+                m.cancel_orders(agent);
+            }
+        }
+
+        if let Some(audit) = &mut self.audit {
+            audit.check(step, &self.agents, &self.markets);
         }
 
         // Update friends
         self.agents.update_friends(&self.markets[..]);
 
         // report values
+        let has_market_makers = self.agents.market_maker_count() > 0;
         for (i, m) in self.markets.iter_mut().enumerate() {
             let i = i as u32;
             report!(reporter, "price"[i], m.price() as f64);
             report!(reporter, "volatility"[i], m.volatility() as f64);
+            if let Some(spread) = m.spread() {
+                report!(reporter, "spread"[i], spread as f64);
+            }
+            if let Some(fundamental) = m.fundamental() {
+                report!(reporter, "fundamental"[i], fundamental as f64);
+            }
+            if let Some(breaker) = m.circuit_breaker() {
+                report!(reporter, "halted"[i], breaker.is_halted() as u8 as f64);
+                if let Some(trigger) = breaker.trigger() {
+                    reporter.report_event(format!(
+                        "market {} halted for {} steps, {}",
+                        i,
+                        breaker.halt_steps(),
+                        trigger
+                    ));
+                }
+            }
+            if let Some(revenue) = m.revenue() {
+                report!(reporter, "fee_revenue"[i], revenue.fees as f64);
+                report!(reporter, "tax_revenue"[i], revenue.taxes as f64);
+            }
+            if has_market_makers {
+                let inventory = self.agents.market_maker_inventory(i as MarketId);
+                report!(reporter, "market_maker_inventory"[i], inventory as f64);
+            }
         }
 
         // for agent in 0..10 {
@@ -84,8 +192,345 @@ impl Simulation {
         //     );
         // }
 
-        report!(reporter, "median_wealth", self.agents.wealth_median(self.markets()) as f64);
+        report!(
+            reporter,
+            "median_wealth",
+            self.agents.wealth_median(self.markets()) as f64
+        );
+        if self.agents.has_margin() {
+            report!(reporter, "liquidations", self.agents.liquidations() as f64);
+            if let Some(leverage) = self.agents.mean_leverage(self.markets()) {
+                report!(reporter, "mean_leverage", leverage as f64);
+            }
+        }
+        if let Some(wealth) = self.agents.mean_fundamentalist_wealth(self.markets()) {
+            report!(reporter, "mean_fundamentalist_wealth", wealth as f64);
+        }
+        if self.agents.has_strategies() {
+            for (i, &strategy) in StrategyKind::ALL.iter().enumerate() {
+                let i = i as u32;
+                let count = self.agents.traders_with(strategy).count();
+                report!(reporter, "traders_by_strategy"[i], count as f64);
+                if let Some(wealth) = self.agents.mean_wealth_with(strategy, self.markets()) {
+                    report!(reporter, "mean_wealth_by_strategy"[i], wealth as f64);
+                }
+            }
+        }
+        if self.agents.has_interest() {
+            report!(reporter, "interest_paid", self.agents.interest_paid());
+        }
+        if self.agents.has_population_changes() {
+            report!(reporter, "bankruptcies", self.agents.bankruptcies() as f64);
+            report!(reporter, "entries", self.agents.entries() as f64);
+            report!(reporter, "population", self.agents.traders().count() as f64);
+        }
+        if has_market_makers {
+            let pnl = self.agents.market_maker_pnl(self.markets());
+            report!(reporter, "market_maker_pnl", pnl as f64);
+        }
+        if let Some(index) = self.agents.index() {
+            report!(reporter, "nav", index.nav(self.markets()) as f64);
+            report!(
+                reporter,
+                "index_premium",
+                index.premium(self.markets()) as f64
+            );
+            let created = self.agents.issued(index.market());
+            report!(reporter, "index_shares_created", created as f64);
+        }
         // report!(reporter, "total cash", self.agents.total_cash());
         // report!(reporter, "total assets", self.agents.total_assets(0) as f64);
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{
+        book::OrderBookMarket,
+        config::{BeliefModel, Distribution, RateChange, StrategyConfig},
+        market::GenoaMarket,
+    };
+
+    /// Two markets with 100 traders and 10 fundamentalists.
+    fn small_config() -> Config {
+        let mut config = Config::default();
+        config.market.market_count = 2;
+        config.agent.agent_count = 100;
+        config.agent.fundamentalist_count = 10;
+        config
+    }
+
+    /// Run a simulation for the given amount of steps, calling `on_step`
+    /// after every step. The accounting is audited and has to hold.
+    fn simulate_with<Mk: Market>(
+        config: &Config,
+        seed: u64,
+        steps: usize,
+        mut on_step: impl FnMut(&Simulation<Mk>),
+    ) -> Simulation<Mk> {
+        let mut reporter = Reporter::new();
+        let mut sim = Simulation::<Mk>::new(config, seed);
+        sim.enable_audit();
+        for step in 0..steps {
+            reporter.set_step(step);
+            sim.step(step, &mut reporter, None);
+            on_step(&sim);
+        }
+        assert_eq!(sim.audit().unwrap().violations(), []);
+        sim
+    }
+
+    fn simulate<Mk: Market>(config: &Config, seed: u64, steps: usize) -> Simulation<Mk> {
+        simulate_with(config, seed, steps, |_| {})
+    }
+
+    fn run<Mk: Market>(seed: u64, steps: usize) -> Vec<f32> {
+        let sim = simulate::<Mk>(&small_config(), seed, steps);
+        sim.markets().iter().map(|m| m.price()).collect()
+    }
+
+    #[test]
+    fn test_same_seed_is_deterministic() {
+        assert_eq!(run::<AnyMarket>(42, 50), run::<AnyMarket>(42, 50));
+        assert_ne!(run::<AnyMarket>(42, 50), run::<AnyMarket>(43, 50));
+    }
+
+    #[test]
+    fn test_simulation_with_concrete_market() {
+        assert_eq!(run::<GenoaMarket>(42, 50), run::<AnyMarket>(42, 50));
+        assert_ne!(run::<OrderBookMarket>(42, 50), run::<GenoaMarket>(42, 50));
+    }
+
+    #[test]
+    fn test_short_positions_balance() {
+        let mut config = Config::default();
+        config.agent.agent_count = 100;
+        config.agent.margin.enabled = true;
+
+        let net_assets = |s: &Simulation<GenoaMarket>| {
+            let agents = s.agents().agents();
+            let short = agents.iter().map(|a| a.short(0)).sum::<u32>();
+            s.agents().total_assets(0) as i64 - short as i64
+        };
+        let assets = net_assets(&Simulation::new(&config, 5));
+        let sim = simulate(&config, 5, 50);
+
+        assert!(sim.agents().agents().iter().any(|a| a.short(0) > 0));
+        assert_eq!(net_assets(&sim), assets);
+    }
+
+    #[test]
+    fn test_arbitrageurs_track_the_index() {
+        let mut config = Config::default();
+        config.market.market_count = 3;
+        config.market.index.market = Some(2);
+        config.agent.agent_count = 200;
+        config.agent.fundamentalist_count = 20;
+
+        let mean_premium = |config: &Config| {
+            let mut premium = 0.0;
+            simulate_with(config, 3, 200, |sim: &Simulation<GenoaMarket>| {
+                premium += sim.agents().index().unwrap().premium(sim.markets()).abs() / 200.0;
+            });
+            premium
+        };
+        let without = mean_premium(&config);
+        config.agent.arbitrageurs.count = 10;
+        let with = mean_premium(&config);
+        assert!(with < without, "{} is not below {}", with, without);
+    }
+
+    #[test]
+    fn test_bankruptcies_and_entries() {
+        let mut config = small_config();
+        config.agent.max_friends = Distribution::static_value(5.0);
+        config.agent.population.enabled = true;
+        config.agent.population.bankruptcy_threshold = 5900.0;
+        config.agent.population.entry_rate = 0.5;
+
+        let (mut bankruptcies, mut entries) = (0, 0);
+        let mut frozen: Vec<(usize, f32)> = Vec::new();
+        let sim = simulate_with(&config, 5, 100, |sim: &Simulation<OrderBookMarket>| {
+            bankruptcies += sim.agents().bankruptcies();
+            entries += sim.agents().entries();
+
+            let agents = sim.agents().agents();
+            for (id, cash) in &frozen {
+                assert_eq!(agents[*id].cash, *cash);
+            }
+            frozen = (0..agents.len())
+                .filter(|&id| agents[id].kind.is_bankrupt())
+                .map(|id| (id, agents[id].cash))
+                .collect();
+            for a in agents {
+                assert!(a
+                    .friends
+                    .iter()
+                    .all(|f| !agents[f.agent()].kind.is_bankrupt()));
+            }
+        });
+
+        assert!(bankruptcies > 0 && entries > 0);
+        assert_eq!(frozen.len(), bankruptcies);
+        let agents = sim.agents();
+        assert_eq!(agents.agents().len(), 110 + entries);
+        let active = agents.traders().count() + agents.fundamentalists().count();
+        assert_eq!(active, 110 + entries - bankruptcies);
+    }
+
+    #[test]
+    fn test_gossip_with_one_trader_left() {
+        let mut config = Config::default();
+        config.agent.agent_count = 1;
+        config.agent.fundamentalist_count = 0;
+        config.agent.influence_probability = Distribution::static_value(1.0);
+        config.agent.influencers_count = Distribution::static_value(3.0);
+
+        // The only trader has nobody to gossip with and keeps its beliefs.
+        let state = Simulation::<OrderBookMarket>::new(&config, 2)
+            .agents()
+            .agents()[0]
+            .state
+            .clone();
+        let sim = simulate::<OrderBookMarket>(&config, 2, 10);
+        assert_eq!(sim.agents().agents()[0].state, state);
+    }
+
+    #[test]
+    fn test_fundamentalist_strategy_moves_price_to_value() {
+        let mut config = Config::default();
+        config.agent.agent_count = 100;
+        config.agent.fundamentalist_count = 10;
+        config.market.fundamental.enabled = true;
+        config.market.fundamental.initial_value = Some(120.0);
+
+        let gap = |config: &Config| {
+            let sim = simulate::<GenoaMarket>(config, 6, 200);
+            (sim.markets()[0].price() - 120.0).abs()
+        };
+        let without = gap(&config);
+        config.agent.strategies = vec![StrategyConfig {
+            strategy: StrategyKind::Fundamentalist,
+            count: 50,
+            ..Default::default()
+        }];
+        let with = gap(&config);
+        assert!(with < without, "{} is not below {}", with, without);
+
+        let sim = Simulation::<GenoaMarket>::new(&config, 6);
+        let agents = sim.agents();
+        assert_eq!(
+            agents.traders_with(StrategyKind::Fundamentalist).count(),
+            50
+        );
+        assert_eq!(agents.traders_with(StrategyKind::NoiseTrader).count(), 50);
+    }
+
+    #[test]
+    fn test_fundamentalists_buy_below_value() {
+        let mut config = Config::default();
+        config.agent.agent_count = 100;
+        config.agent.fundamentalist_count = 20;
+        config.market.fundamental.enabled = true;
+        config.market.fundamental.initial_value = Some(150.0);
+
+        // Fundamentalists that do not trade hold nothing.
+        let sim = Simulation::<GenoaMarket>::new(&config, 8);
+        let (agents, markets) = (sim.agents(), sim.markets());
+        assert!(agents.fundamentalists().all(|a| a.wealth(markets) == 0.0));
+        assert!(agents.mean_fundamentalist_wealth(markets).is_none());
+        config.agent.fundamentalists.trading = true;
+
+        let sim = simulate::<GenoaMarket>(&config, 8, 20);
+        let agents = sim.agents();
+        assert_eq!(agents.fundamentalists().count(), 20);
+        assert!(sim.markets()[0].price() < 150.0);
+        let assets = agents.fundamentalists().map(|a| a.assets[0]).sum::<u32>();
+        assert!(assets > 20 * 30, "{} assets", assets);
+        assert!(agents.mean_fundamentalist_wealth(sim.markets()).is_some());
+    }
+
+    #[test]
+    fn test_belief_models() {
+        let mut config = Config::default();
+        config.agent.agent_count = 100;
+        config.agent.fundamentalist_count = 10;
+        for model in [
+            BeliefModel::Rounded,
+            BeliefModel::DeGroot,
+            BeliefModel::BoundedConfidence,
+            BeliefModel::Majority,
+        ] {
+            config.agent.beliefs.model = model;
+            let sim = simulate::<GenoaMarket>(&config, 9, 50);
+            let states = sim
+                .agents()
+                .traders()
+                .map(|a| a.state[0])
+                .collect::<Vec<_>>();
+            let continuous = states.iter().any(|&s| s != 0.0 && s != 1.0);
+            assert!(states.iter().all(|s| (0.0..=1.0).contains(s)));
+            assert_eq!(continuous, model == BeliefModel::DeGroot, "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn test_interest_on_cash() {
+        let mut config = Config::default();
+        config.agent.agent_count = 10;
+        config.agent.order_probability = Distribution::static_value(0.0);
+        config.agent.margin.enabled = true;
+        config.interest.rate = 0.001;
+        config.interest.borrow_spread = 0.004;
+        config.interest.schedule = vec![RateChange {
+            step: 5,
+            rate: 0.002,
+        }];
+
+        let sim = simulate::<GenoaMarket>(&config, 4, 10);
+        let expected = 3000.0 * 1.001f32.powi(5) * 1.002f32.powi(5);
+        for a in sim.agents().traders() {
+            assert!((a.cash - expected).abs() < 1e-2, "{}", a.cash);
+        }
+        let paid = sim.agents().interest_paid();
+        assert!((paid - 10.0 * expected as f64 * 0.002 / 1.002).abs() < 1e-1);
+
+        // Borrowed cash costs the rate plus the spread.
+        let markets = vec![GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0))];
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        agents.agent_mut(0).cash = -1000.0;
+        agents.step(&markets, 0);
+        assert!((agents.agent(0).cash + 1005.0).abs() < 1e-3);
+        assert!((agents.agent(1).cash - 3003.0).abs() < 1e-3);
+
+        // Without a margin account nothing is charged on an overdraft.
+        config.agent.margin.enabled = false;
+        let mut agents = AgentCollection::<5>::new(&config, SimRng::seed_from_u64(0));
+        agents.agent_mut(0).cash = -1000.0;
+        agents.step(&markets, 0);
+        assert_eq!(agents.agent(0).cash, -1000.0);
+        assert!((agents.agent(1).cash - 3003.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_market_makers_quote_both_sides() {
+        let mut config = small_config();
+        config.agent.market_makers.count = 3;
+
+        let assets = Simulation::<OrderBookMarket>::new(&config, 1)
+            .agents()
+            .total_assets(0);
+        let sim = simulate::<OrderBookMarket>(&config, 1, 50);
+        assert_eq!(sim.agents().market_maker_count(), 3);
+        assert_eq!(sim.agents().total_assets(0), assets);
+        assert!(sim.agents().market_maker_pnl(sim.markets()).is_finite());
+
+        // Without other orders, the quotes are two volatilities away from the price.
+        config.agent.order_probability = Distribution::static_value(0.0);
+        let sim = simulate::<OrderBookMarket>(&config, 1, 1);
+        for m in sim.markets() {
+            assert!((m.spread().unwrap() - 1.2).abs() < 1e-3);
+        }
+    }
+}
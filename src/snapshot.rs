@@ -0,0 +1,96 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use toml::{from_str, to_string};
+
+use crate::{config::Config, report::Reporter, simulation::Simulation};
+
+/// The complete state of a running simulation, this can be written to a file
+/// and used to continue the run later on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    /// The configuration is stored as toml, because the internally tagged
+    /// distributions can not be stored in bincode.
+    config: String,
+    pub seed: u64,
+    /// The next step that will be simulated.
+    pub step: usize,
+    pub sim: Simulation,
+    pub reporter: Reporter,
+}
+
+impl Snapshot {
+    pub fn new(
+        config: &Config,
+        seed: u64,
+        step: usize,
+        sim: Simulation,
+        reporter: Reporter,
+    ) -> Result<Snapshot, Box<dyn Error>> {
+        Ok(Snapshot {
+            config: to_string(config)?,
+            seed,
+            step,
+            sim,
+            reporter,
+        })
+    }
+
+    pub fn config(&self) -> Result<Config, Box<dyn Error>> {
+        Ok(from_str(&self.config)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Snapshot, Box<dyn Error>> {
+        let file = BufReader::new(File::open(path)?);
+        Ok(bincode::deserialize_from(file)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let file = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::market::Market;
+
+    fn step(sim: &mut Simulation, reporter: &mut Reporter, steps: std::ops::Range<usize>) {
+        for step in steps {
+            reporter.set_step(step);
+            sim.step(step, reporter, None);
+        }
+    }
+
+    #[test]
+    fn test_resume_matches_uninterrupted_run() {
+        let mut config = Config::default();
+        config.market.market_count = 2;
+        config.agent.agent_count = 100;
+        config.agent.max_friends = crate::config::Distribution::static_value(3.0);
+
+        let mut sim: Simulation = Simulation::new(&config, 3);
+        let mut reporter = Reporter::new();
+        step(&mut sim, &mut reporter, 0..30);
+
+        let snapshot = Snapshot::new(&config, 3, 30, sim.clone(), reporter.clone()).unwrap();
+        let bytes = bincode::serialize(&snapshot).unwrap();
+        let snapshot: Snapshot = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(snapshot.config().unwrap().agent.agent_count, 100);
+
+        let (mut resumed, mut resumed_reporter) = (snapshot.sim, snapshot.reporter);
+        step(&mut sim, &mut reporter, 30..60);
+        step(&mut resumed, &mut resumed_reporter, 30..60);
+
+        let prices = |s: &Simulation| s.markets().iter().map(|m| m.price()).collect::<Vec<_>>();
+        assert_eq!(prices(&sim), prices(&resumed));
+        assert_eq!(sim.agents().total_cash(), resumed.agents().total_cash());
+    }
+}
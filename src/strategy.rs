@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agent::Agent,
+    config::{StrategyConfig, StrategyKind},
+    market::Market,
+    simulation::SimRng,
+};
+
+/// Decides in which direction a trader trades. The size of the orders and
+/// whether an order is placed at all do not depend on the strategy.
+pub trait Strategy {
+    /// The belief of the trader about a market, between 0 and 1. The trader
+    /// buys with this probability and sells otherwise, the market may use it
+    /// for the limit price of the order.
+    fn belief<Mk: Market, const M: usize>(&self, agent: &Agent<M>, market: &Mk) -> f32;
+}
+
+/// Trades on the belief it formed by gossip.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NoiseTrader;
+
+impl Strategy for NoiseTrader {
+    fn belief<Mk: Market, const M: usize>(&self, agent: &Agent<M>, market: &Mk) -> f32 {
+        agent.state[market.id()]
+    }
+}
+
+/// Buys after the price rose over the horizon and sells after it fell.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chartist {
+    horizon: usize,
+    sensitivity: f32,
+}
+
+impl Strategy for Chartist {
+    fn belief<Mk: Market, const M: usize>(&self, _agent: &Agent<M>, market: &Mk) -> f32 {
+        (0.5 + self.sensitivity * trend(market, self.horizon)).clamp(0.0, 1.0)
+    }
+}
+
+/// Sells after the price rose over the horizon and buys after it fell.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contrarian {
+    horizon: usize,
+    sensitivity: f32,
+}
+
+impl Strategy for Contrarian {
+    fn belief<Mk: Market, const M: usize>(&self, _agent: &Agent<M>, market: &Mk) -> f32 {
+        (0.5 - self.sensitivity * trend(market, self.horizon)).clamp(0.0, 1.0)
+    }
+}
+
+/// Buys when the price is below its estimate of the value and sells when it
+/// is above. The estimate is the fundamental value of the market, or the
+/// initial price for markets without one, off by a relative error.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fundamentalist {
+    value_error: f32,
+    sensitivity: f32,
+    initial_price: f32,
+}
+
+impl Fundamentalist {
+    pub fn value_estimate<Mk: Market>(&self, market: &Mk) -> f32 {
+        market.fundamental().unwrap_or(self.initial_price) * (1.0 + self.value_error)
+    }
+}
+
+impl Strategy for Fundamentalist {
+    fn belief<Mk: Market, const M: usize>(&self, _agent: &Agent<M>, market: &Mk) -> f32 {
+        let mispricing = self.value_estimate(market) / market.price() - 1.0;
+        (0.5 + self.sensitivity * mispricing).clamp(0.0, 1.0)
+    }
+}
+
+/// The relative change of the price over the horizon, or over the whole
+/// price history if it is shorter. Without a previous price there is no
+/// trend.
+fn trend<Mk: Market>(market: &Mk, horizon: usize) -> f32 {
+    let len = market.price_history().len();
+    if len < 2 {
+        return 0.0;
+    }
+    let horizon = horizon.clamp(1, len - 1);
+    market.price() / market.price_ago(horizon) - 1.0
+}
+
+/// Any of the strategies above.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AnyStrategy {
+    NoiseTrader(NoiseTrader),
+    Chartist(Chartist),
+    Contrarian(Contrarian),
+    Fundamentalist(Fundamentalist),
+}
+
+impl AnyStrategy {
+    /// A strategy with the parameters drawn from the config.
+    pub fn new(config: &StrategyConfig, initial_price: f32, rng: &mut SimRng) -> AnyStrategy {
+        match config.strategy {
+            StrategyKind::NoiseTrader => AnyStrategy::NoiseTrader(NoiseTrader),
+            StrategyKind::Chartist => AnyStrategy::Chartist(Chartist {
+                horizon: config.horizon.sample_usize(rng),
+                sensitivity: config.sensitivity.sample_f32(rng),
+            }),
+            StrategyKind::Contrarian => AnyStrategy::Contrarian(Contrarian {
+                horizon: config.horizon.sample_usize(rng),
+                sensitivity: config.sensitivity.sample_f32(rng),
+            }),
+            StrategyKind::Fundamentalist => AnyStrategy::Fundamentalist(Fundamentalist {
+                value_error: config.value_error.sample_f32(rng),
+                sensitivity: config.sensitivity.sample_f32(rng),
+                initial_price,
+            }),
+        }
+    }
+
+    pub fn kind(&self) -> StrategyKind {
+        match self {
+            AnyStrategy::NoiseTrader(_) => StrategyKind::NoiseTrader,
+            AnyStrategy::Chartist(_) => StrategyKind::Chartist,
+            AnyStrategy::Contrarian(_) => StrategyKind::Contrarian,
+            AnyStrategy::Fundamentalist(_) => StrategyKind::Fundamentalist,
+        }
+    }
+}
+
+impl Default for AnyStrategy {
+    fn default() -> Self {
+        AnyStrategy::NoiseTrader(NoiseTrader)
+    }
+}
+
+impl Strategy for AnyStrategy {
+    fn belief<Mk: Market, const M: usize>(&self, agent: &Agent<M>, market: &Mk) -> f32 {
+        match self {
+            AnyStrategy::NoiseTrader(s) => s.belief(agent, market),
+            AnyStrategy::Chartist(s) => s.belief(agent, market),
+            AnyStrategy::Contrarian(s) => s.belief(agent, market),
+            AnyStrategy::Fundamentalist(s) => s.belief(agent, market),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{config::Config, market::GenoaMarket};
+
+    #[test]
+    fn test_beliefs() {
+        let mut config = Config::default();
+        config.market.fundamental.enabled = true;
+        config.market.fundamental.initial_value = Some(101.0);
+        let mut rng = SimRng::seed_from_u64(0);
+        let agent = Agent::<5>::new(&config.agent, 1, &mut rng);
+        let mut market = GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0));
+        let strategy = |strategy, rng: &mut SimRng| {
+            let config = StrategyConfig {
+                strategy,
+                ..StrategyConfig::default()
+            };
+            AnyStrategy::new(&config, 100.0, rng)
+        };
+        let chartist = strategy(StrategyKind::Chartist, &mut rng);
+        let contrarian = strategy(StrategyKind::Contrarian, &mut rng);
+        let fundamentalist = strategy(StrategyKind::Fundamentalist, &mut rng);
+
+        // Without a trend chartists and contrarians are undecided.
+        assert_eq!(chartist.belief(&agent, &market), 0.5);
+        assert_eq!(contrarian.belief(&agent, &market), 0.5);
+        assert_eq!(
+            AnyStrategy::default().belief(&agent, &market),
+            agent.state[0]
+        );
+
+        // A price below the value of 101 is a reason to buy.
+        assert!((fundamentalist.belief(&agent, &market) - 0.52).abs() < 1e-3);
+
+        market.jump_price(0.02);
+        assert!((chartist.belief(&agent, &market) - 0.54).abs() < 1e-3);
+        assert!((contrarian.belief(&agent, &market) - 0.46).abs() < 1e-3);
+        assert!((fundamentalist.belief(&agent, &market) - 0.4804).abs() < 1e-3);
+
+        // A history of a single price has no trend.
+        config.market.price_history_count = 1;
+        let mut market = GenoaMarket::new(&config, 0, SimRng::seed_from_u64(0));
+        market.record_price(102.0);
+        assert_eq!(market.price_history().len(), 1);
+        assert_eq!(chartist.belief(&agent, &market), 0.5);
+        assert_eq!(contrarian.belief(&agent, &market), 0.5);
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufWriter, Write},
+    path::Path,
+};
+
+use rand::SeedableRng;
+use toml::{from_str, to_string};
+
+use crate::{
+    agent::AgentId,
+    config::Config,
+    market::{GenoaMarket, Market, MarketId, Side},
+    simulation::SimRng,
+};
+
+/// A line delimited log of every order and execution in a run. The tape starts
+/// with the configuration of the run as toml, in lines starting with `#`, so
+/// the markets can be rebuilt for a replay. Every other line starts with a tag
+/// followed by space separated values:
+///
+/// * `O step market agent side quantity limit_price` for a submitted order,
+///   the limit price is `-` for market orders.
+/// * `C step market price quantity` when a market is cleared.
+/// * `X step market agent side quantity price` for an executed (partial) order.
+/// * `L step market price quantity` at the end of a step of an order book
+///   market, with the last traded price and the traded quantity.
+/// * `H step market price` instead of a clearing, when trading in a call
+///   auction market is halted and the orders are discarded.
+///
+/// The side is either `B` or `S`. Orders of a call auction market are always
+/// written before the clearing they belong to, executions after it. Order book
+/// markets write the executions of an order directly after the order.
+pub struct TradeTape {
+    out: Box<dyn Write + Send>,
+    step: usize,
+}
+
+impl TradeTape {
+    pub fn create(path: impl AsRef<Path>, config: &Config) -> Result<TradeTape, Box<dyn Error>> {
+        let mut tape = TradeTape::new(BufWriter::new(File::create(path)?));
+        tape.write_config(config)?;
+        Ok(tape)
+    }
+
+    /// Continues an existing tape, or creates it if there is none.
+    pub fn append(path: impl AsRef<Path>, config: &Config) -> Result<TradeTape, Box<dyn Error>> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut tape = TradeTape::new(BufWriter::new(file));
+        if empty {
+            tape.write_config(config)?;
+        }
+        Ok(tape)
+    }
+
+    pub fn new(out: impl Write + Send + 'static) -> TradeTape {
+        TradeTape {
+            out: Box::new(out),
+            step: 0,
+        }
+    }
+
+    /// Write the configuration of the run, before any other line.
+    pub fn write_config(&mut self, config: &Config) -> Result<(), Box<dyn Error>> {
+        for line in to_string(config)?.lines() {
+            writeln!(self.out, "# {}", line)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_step(&mut self, step: usize) {
+        self.step = step;
+    }
+
+    pub fn order(
+        &mut self,
+        market: MarketId,
+        agent: AgentId,
+        side: Side,
+        quantity: u32,
+        limit_price: Option<f32>,
+    ) {
+        let limit_price = limit_price.map_or_else(|| "-".to_string(), |p| p.to_string());
+        writeln!(
+            self.out,
+            "O {} {} {} {} {} {}",
+            self.step,
+            market,
+            agent,
+            side_tag(side),
+            quantity,
+            limit_price
+        )
+        .expect("can't write trade tape");
+    }
+
+    pub fn clearing(&mut self, market: MarketId, price: f32, quantity: u32) {
+        writeln!(
+            self.out,
+            "C {} {} {} {}",
+            self.step, market, price, quantity
+        )
+        .expect("can't write trade tape");
+    }
+
+    pub fn book_step(&mut self, market: MarketId, price: f32, quantity: u32) {
+        writeln!(
+            self.out,
+            "L {} {} {} {}",
+            self.step, market, price, quantity
+        )
+        .expect("can't write trade tape");
+    }
+
+    pub fn halt(&mut self, market: MarketId, price: f32) {
+        writeln!(self.out, "H {} {} {}", self.step, market, price).expect("can't write trade tape");
+    }
+
+    pub fn execution(
+        &mut self,
+        market: MarketId,
+        agent: AgentId,
+        side: Side,
+        quantity: u32,
+        price: f32,
+    ) {
+        writeln!(
+            self.out,
+            "X {} {} {} {} {} {}",
+            self.step,
+            market,
+            agent,
+            side_tag(side),
+            quantity,
+            price
+        )
+        .expect("can't write trade tape");
+    }
+
+    pub fn flush(&mut self) {
+        self.out.flush().expect("can't write trade tape");
+    }
+}
+
+fn side_tag(side: Side) -> &'static str {
+    match side {
+        Side::Buy => "B",
+        Side::Sell => "S",
+    }
+}
+
+fn parse_side(tag: &str) -> Result<Side, Box<dyn Error>> {
+    match tag {
+        "B" => Ok(Side::Buy),
+        "S" => Ok(Side::Sell),
+        _ => Err(format!("unknown side '{}'", tag).into()),
+    }
+}
+
+/// A clearing where the replayed result differs from the recorded one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayMismatch {
+    pub step: usize,
+    pub market: MarketId,
+    pub recorded: (f32, u32),
+    pub replayed: (f32, u32),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayResult {
+    pub clearings: usize,
+    pub orders: usize,
+    pub executions: usize,
+    /// Steps of order book markets, these are not checked.
+    pub book_steps: usize,
+    /// Steps of call auction markets where trading was halted.
+    pub halted_steps: usize,
+    pub mismatches: Vec<ReplayMismatch>,
+    /// Clearings where the executions on one side do not add up to the
+    /// recorded quantity, as `(step, market, side)`.
+    pub unbalanced: Vec<(usize, MarketId, Side)>,
+}
+
+/// Reads a trade tape and feeds the recorded orders through the clearing logic
+/// of [`GenoaMarket`], comparing the result with the recorded clearings. The
+/// markets are built from the configuration at the start of the tape, or the
+/// default configuration if it has none. Order book markets are skipped.
+pub fn replay(tape: impl BufRead) -> Result<ReplayResult, Box<dyn Error>> {
+    let mut lines = tape.lines().enumerate().peekable();
+    let mut header = String::new();
+    while let Some((_, Ok(line))) = lines.peek() {
+        match line.strip_prefix('#') {
+            Some(line) => {
+                header.push_str(line.strip_prefix(' ').unwrap_or(line));
+                header.push('\n');
+                lines.next();
+            }
+            None => break,
+        }
+    }
+    let config = match header.trim() {
+        "" => Config::default(),
+        _ => from_str(&header)?,
+    };
+
+    let mut result = ReplayResult::default();
+    let mut markets = HashMap::<MarketId, GenoaMarket>::new();
+    // The last clearing per market and the executions seen since.
+    let mut last_clearing = HashMap::<MarketId, (usize, f32, u32)>::new();
+    let mut executed = HashMap::<(MarketId, Side), u32>::new();
+
+    for (line_nr, line) in lines {
+        let line = line?;
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let bad_line = || format!("malformed trade tape line {}: '{}'", line_nr + 1, line);
+
+        match fields.as_slice() {
+            ["O", _step, market, agent, side, quantity, limit_price] => {
+                let market = market.parse()?;
+                markets
+                    .entry(market)
+                    .or_insert_with(|| GenoaMarket::new(&config, market, SimRng::seed_from_u64(0)))
+                    .submit(
+                        agent.parse()?,
+                        parse_side(side)?,
+                        quantity.parse()?,
+                        match *limit_price {
+                            "-" => None,
+                            p => Some(p.parse()?),
+                        },
+                    );
+                result.orders += 1;
+            }
+            ["C", step, market, price, quantity] => {
+                let step: usize = step.parse()?;
+                let market: MarketId = market.parse()?;
+                let recorded = (price.parse::<f32>()?, quantity.parse::<u32>()?);
+
+                check_executions(
+                    &mut result,
+                    &mut executed,
+                    market,
+                    last_clearing.get(&market),
+                );
+
+                // The price is kept for clearings with only market orders at
+                // the margin.
+                let cleared = markets.get_mut(&market).and_then(|m| {
+                    let cleared = m.clearing();
+                    m.clear_orders();
+                    m.record_price(recorded.0);
+                    cleared
+                });
+                // Without a deal the price stays the same, which is only known
+                // if the market was cleared before.
+                let replayed = match cleared {
+                    Some(c) => Some(c),
+                    None => last_clearing.get(&market).map(|&(_, p, _)| (p, 0)),
+                };
+
+                if let Some(replayed) = replayed {
+                    if replayed != recorded {
+                        result.mismatches.push(ReplayMismatch {
+                            step,
+                            market,
+                            recorded,
+                            replayed,
+                        });
+                    }
+                }
+
+                last_clearing.insert(market, (step, recorded.0, recorded.1));
+                result.clearings += 1;
+            }
+            ["H", step, market, price] => {
+                let step: usize = step.parse()?;
+                let market: MarketId = market.parse()?;
+
+                check_executions(
+                    &mut result,
+                    &mut executed,
+                    market,
+                    last_clearing.get(&market),
+                );
+                if let Some(m) = markets.get_mut(&market) {
+                    m.clear_orders();
+                }
+
+                last_clearing.insert(market, (step, price.parse()?, 0));
+                result.halted_steps += 1;
+            }
+            ["L", _step, market, _price, _quantity] => {
+                let market: MarketId = market.parse()?;
+                markets.remove(&market);
+                executed.retain(|&(m, _), _| m != market);
+                result.book_steps += 1;
+            }
+            ["X", _step, market, _agent, side, quantity, _price] => {
+                *executed
+                    .entry((market.parse()?, parse_side(side)?))
+                    .or_default() += quantity.parse::<u32>()?;
+                result.executions += 1;
+            }
+            [] => {}
+            [tag, ..] if tag.starts_with('#') => {}
+            _ => return Err(bad_line().into()),
+        }
+    }
+
+    for (&market, clearing) in &last_clearing {
+        check_executions(&mut result, &mut executed, market, Some(clearing));
+    }
+
+    Ok(result)
+}
+
+/// Checks that the executions after a clearing add up to the cleared quantity.
+fn check_executions(
+    result: &mut ReplayResult,
+    executed: &mut HashMap<(MarketId, Side), u32>,
+    market: MarketId,
+    clearing: Option<&(usize, f32, u32)>,
+) {
+    if let Some(&(step, _, quantity)) = clearing {
+        for side in [Side::Buy, Side::Sell] {
+            if executed.remove(&(market, side)).unwrap_or(0) != quantity {
+                result.unbalanced.push((step, market, side));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{
+        io::{self, Cursor},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{config::GridConfig, report::Reporter, simulation::Simulation};
+
+    /// A writer that can be read after the tape is done with it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Record a run of 20 steps and replay it.
+    fn record_and_replay(config: &Config) -> ReplayResult {
+        let buffer = SharedBuffer::default();
+        let mut tape = TradeTape::new(buffer.clone());
+        tape.write_config(config).unwrap();
+        let mut reporter = Reporter::new();
+        let mut sim: Simulation = Simulation::new(config, 11);
+        for step in 0..20 {
+            reporter.set_step(step);
+            tape.set_step(step);
+            sim.step(step, &mut reporter, Some(&mut tape));
+        }
+        tape.flush();
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        replay(Cursor::new(bytes)).unwrap()
+    }
+
+    #[test]
+    fn test_replay_reproduces_prices() {
+        let mut config = Config::default();
+        config.market.market_count = 2;
+        config.agent.agent_count = 100;
+
+        let result = record_and_replay(&config);
+        assert_eq!(result.clearings, 40);
+        assert!(result.orders > 0);
+        assert!(result.executions > 0);
+        assert_eq!(result.mismatches, []);
+        assert_eq!(result.unbalanced, []);
+    }
+
+    #[test]
+    fn test_replay_uses_the_grid_of_the_run() {
+        let mut config = Config::default();
+        config.agent.agent_count = 100;
+        config.market.grids = vec![GridConfig {
+            tick_size: Some(0.3),
+            lot_size: 2,
+        }];
+
+        let result = record_and_replay(&config);
+        assert_eq!(result.clearings, 20);
+        assert!(result.executions > 0);
+        assert_eq!(result.mismatches, []);
+        assert_eq!(result.unbalanced, []);
+    }
+
+    #[test]
+    fn test_replay_detects_mismatch() {
+        let tape = "O 0 0 1 B 5 1.1\nO 0 0 2 S 5 0.9\nC 0 0 2 5\n";
+        let result = replay(Cursor::new(tape)).unwrap();
+        assert_eq!(result.mismatches.len(), 1);
+        assert_eq!(result.mismatches[0].replayed, (1.0, 5));
+    }
+
+    #[test]
+    fn test_replay_discards_halted_orders() {
+        let tape = "O 0 0 1 B 5 1.1\nO 0 0 2 S 5 0.9\nH 0 0 1\nC 1 0 1 0\n";
+        let result = replay(Cursor::new(tape)).unwrap();
+        assert_eq!(result.halted_steps, 1);
+        assert_eq!(result.mismatches, []);
+        assert_eq!(result.unbalanced, []);
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{VolatilityConfig, VolatilityModel},
+    market::log_return_volatility,
+};
+
+/// Estimates the volatility of a market, see [`VolatilityConfig`] for the
+/// models.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolatilityEstimator {
+    model: VolatilityModel,
+    decay: f32,
+    omega: f32,
+    alpha: f32,
+    beta: f32,
+    /// The estimated variance of the log returns, not used by the rolling
+    /// window.
+    variance: f32,
+}
+
+impl VolatilityEstimator {
+    pub fn new(config: &VolatilityConfig, initial_volatility: f32) -> VolatilityEstimator {
+        let variance = initial_volatility * initial_volatility;
+        VolatilityEstimator {
+            model: config.model,
+            decay: config.decay,
+            omega: config
+                .omega
+                .unwrap_or(variance * (1.0 - config.alpha - config.beta)),
+            alpha: config.alpha,
+            beta: config.beta,
+            variance,
+        }
+    }
+
+    /// Update the estimate after a new price was recorded, the history starts
+    /// with the current price. Returns nothing if the history is too short.
+    pub fn update(&mut self, price_history: &VecDeque<f32>) -> Option<f32> {
+        let squared = || {
+            let log_return = (price_history.front()? / price_history.get(1)?).ln();
+            Some(log_return * log_return)
+        };
+        self.variance = match self.model {
+            VolatilityModel::Rolling => return log_return_volatility(price_history),
+            VolatilityModel::Ewma => self.decay * self.variance + (1.0 - self.decay) * squared()?,
+            VolatilityModel::Garch => {
+                self.omega + self.alpha * squared()? + self.beta * self.variance
+            }
+        };
+        Some(self.variance.sqrt())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_estimators() {
+        let prices = |p: &[f32]| p.iter().copied().collect::<VecDeque<_>>();
+        let config = |model| VolatilityConfig {
+            model,
+            ..VolatilityConfig::default()
+        };
+
+        let mut rolling = VolatilityEstimator::new(&config(VolatilityModel::Rolling), 0.1);
+        assert_eq!(rolling.update(&prices(&[1.0, 1.0])), None);
+        assert_eq!(rolling.update(&prices(&[1.0, 1.0, 1.0])), Some(0.0));
+
+        // Without price changes the EWMA decays towards zero, GARCH towards
+        // the long run variance.
+        let mut ewma = VolatilityEstimator::new(&config(VolatilityModel::Ewma), 0.1);
+        let mut garch = VolatilityEstimator::new(&config(VolatilityModel::Garch), 0.1);
+        for _ in 0..100 {
+            ewma.update(&prices(&[1.0, 1.0]));
+            garch.update(&prices(&[1.0, 1.0]));
+        }
+        assert!(ewma.update(&prices(&[1.0, 1.0])).unwrap() < 0.01);
+        let long_run = 0.1 * (0.05f32 / 0.15).sqrt();
+        assert!((garch.update(&prices(&[1.0, 1.0])).unwrap() - long_run).abs() < 1e-4);
+
+        // A large return raises the GARCH estimate by alpha times its square.
+        let before = garch.variance;
+        let shock = 1.1f32.ln();
+        let after = garch.update(&prices(&[1.1, 1.0])).unwrap();
+        let expected = garch.omega + 0.1 * shock * shock + 0.85 * before;
+        assert!((after * after - expected).abs() < 1e-6);
+    }
+}
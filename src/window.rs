@@ -10,23 +10,24 @@ use speedy2d::shape::Rectangle;
 use speedy2d::window::{WindowHandler, WindowHelper};
 use speedy2d::Graphics2D;
 
+use crate::market::{AnyMarket, Market};
 use crate::report::Reporter;
 use crate::simulation::Simulation;
 
 #[derive(Debug)]
-pub struct Data {
-    pub sim: Simulation,
+pub struct Data<Mk = AnyMarket> {
+    pub sim: Simulation<Mk>,
     pub report: Reporter,
 }
 
 #[derive(Debug)]
-pub struct MyWindowHandler {
+pub struct MyWindowHandler<Mk = AnyMarket> {
     size: Vector2<f32>,
-    data: Option<Data>,
+    data: Option<Data<Mk>>,
 }
 
-impl WindowHandler<Data> for MyWindowHandler {
-    fn on_draw(&mut self, _helper: &mut WindowHelper<Data>, graphics: &mut Graphics2D) {
+impl<Mk: Market> WindowHandler<Data<Mk>> for MyWindowHandler<Mk> {
+    fn on_draw(&mut self, _helper: &mut WindowHelper<Data<Mk>>, graphics: &mut Graphics2D) {
         let data = if let Some(data) = self.data.as_ref() {
             data
         } else {
@@ -96,18 +97,18 @@ impl WindowHandler<Data> for MyWindowHandler {
         // data.report.render_chart(backend.into_drawing_area());
     }
 
-    fn on_user_event(&mut self, helper: &mut WindowHelper<Data>, user_event: Data) {
+    fn on_user_event(&mut self, helper: &mut WindowHelper<Data<Mk>>, user_event: Data<Mk>) {
         self.data = Some(user_event);
         helper.request_redraw();
     }
 
-    fn on_resize(&mut self, helper: &mut WindowHelper<Data>, size_pixels: Vector2<u32>) {
+    fn on_resize(&mut self, helper: &mut WindowHelper<Data<Mk>>, size_pixels: Vector2<u32>) {
         self.size = size_pixels.into_f32();
         helper.request_redraw();
     }
 }
 
-impl Default for MyWindowHandler {
+impl<Mk> Default for MyWindowHandler<Mk> {
     fn default() -> Self {
         MyWindowHandler {
             size: Vector2::ZERO,
@@ -188,7 +189,7 @@ impl Rect {
         let area = self.width() * self.height();
         let area_cell = area / count as f32;
         let y_count = (self.width() / area_cell.sqrt()).round() as usize;
-        let x_count = count / y_count + (count % y_count != 0) as usize;
+        let x_count = count / y_count + !count.is_multiple_of(y_count) as usize;
 
         self.split_horizontal(x_count)
             .flat_map(move |r| r.split_vertical(y_count))
@@ -334,10 +335,7 @@ impl DrawingBackend for PlottersBackendSpeedy<'_> {
             let mut begin: Option<BackendCoord> = None;
             for end in path.into_iter() {
                 if let Some(begin) = begin {
-                    let result = self.draw_line(begin, end, style);
-                    if result.is_err() {
-                        return result;
-                    }
+                    self.draw_line(begin, end, style)?;
                 }
                 begin = Some(end);
             }
@@ -410,7 +408,7 @@ impl DrawingBackend for PlottersBackendSpeedy<'_> {
         _style: &TStyle,
         _pos: BackendCoord,
     ) -> Result<(), DrawingErrorKind<Self::ErrorType>> {
-        return Ok(());
+        Ok(())
         // let font = Font::new(include_bytes!("../Mechanical.otf")).unwrap();
         // let color = style.color();
         // let color = Color::from_rgba(
@@ -453,11 +451,11 @@ impl DrawingBackend for PlottersBackendSpeedy<'_> {
         ))
     }
 
-    fn blit_bitmap<'a>(
+    fn blit_bitmap(
         &mut self,
         _pos: BackendCoord,
         (_iw, _ih): (u32, u32),
-        _src: &'a [u8],
+        _src: &[u8],
     ) -> Result<(), DrawingErrorKind<Self::ErrorType>> {
         unimplemented!()
     }